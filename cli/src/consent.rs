@@ -0,0 +1,171 @@
+//! Asking before a file is written: the receiver's terminal prompt and the sender's wait for
+//! its `accept` or `decline`.
+
+use anyhow::{anyhow, Result};
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, OnceLock, Weak};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Duration;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::events::log_line;
+use crate::{accept_meta, DataMessage, ReceiveProgress};
+
+// How long a receiver has to answer the accept prompt before the file counts as declined.
+pub(crate) const CONSENT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+// The receiver gives up on its own prompt first, so the sender hears a `decline` rather than
+// timing out.
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+
+static PROMPT_LINES: OnceLock<Mutex<mpsc::UnboundedReceiver<String>>> = OnceLock::new();
+
+/// An offered file that passed the checks, waiting to be opened once accepted.
+pub(crate) struct MetaOffer {
+  pub(crate) name: String,
+  pub(crate) safe_name: String,
+  pub(crate) size: u64,
+  pub(crate) mime: String,
+  pub(crate) encrypted: bool,
+  pub(crate) snippet: bool,
+  // Collected in memory and printed instead of written to a file.
+  pub(crate) printed: bool,
+}
+
+pub(crate) async fn send_control(dc: &Weak<RTCDataChannel>, msg: &DataMessage) {
+  if let (Some(dc), Ok(text)) = (dc.upgrade(), serde_json::to_string(msg)) {
+    let _ = dc.send_text(text).await;
+  }
+}
+
+pub(crate) fn decline(reason: &str) -> DataMessage {
+  DataMessage::Decline {
+    reason: Some(reason.to_string()),
+  }
+}
+
+pub(crate) fn prompt_available() -> bool {
+  std::io::stdin().is_terminal() && std::io::stderr().is_terminal()
+}
+
+/// Asks about `offer` on a task of its own, then accepts or declines it. Waiting in the channel's
+/// message handler would hold up every later message, the sender giving up included.
+pub(crate) fn spawn_confirm(progress: Arc<Mutex<ReceiveProgress>>, reply_dc: Weak<RTCDataChannel>, offer: MetaOffer) {
+  tokio::spawn(async move {
+    match confirm_file(&offer).await {
+      Some(reason) => {
+        log_line("[recv] declined", &format!("{} ({reason})", offer.name));
+        send_control(&reply_dc, &decline(&reason)).await;
+        progress.lock().await.decline(&offer.safe_name, &reason).await;
+      }
+      None => accept_meta(&mut *progress.lock().await, &reply_dc, offer).await,
+    }
+  });
+}
+
+/// Shows what the sender offers and asks on the terminal. Returns why the file was declined:
+/// anything but y/yes, or no answer within `CONFIRM_TIMEOUT`.
+async fn confirm_file(offer: &MetaOffer) -> Option<String> {
+  let described = if offer.snippet {
+    format!("text snippet ({} bytes)", offer.size)
+  } else {
+    format!("{} ({}, {} bytes)", offer.safe_name, offer.mime, offer.size)
+  };
+  let lines = PROMPT_LINES.get_or_init(|| {
+    // One plain thread for the whole run, like `session`'s stdin reader, so a pending read never
+    // holds up exit and a prompt that timed out leaves no reader behind to take the next answer.
+    let (line_tx, line_rx) = mpsc::unbounded_channel::<String>();
+    std::thread::spawn(move || {
+      for line in std::io::stdin().lines().map_while(Result::ok) {
+        if line_tx.send(line).is_err() {
+          break;
+        }
+      }
+    });
+    Mutex::new(line_rx)
+  });
+  let mut lines = lines.lock().await;
+  // Lines typed while no prompt was showing do not answer this one.
+  while lines.try_recv().is_ok() {}
+  eprint!("Incoming: {described}\nAccept? [y/N] ");
+  let _ = std::io::stderr().flush();
+  match tokio::time::timeout(CONFIRM_TIMEOUT, lines.recv()).await {
+    Ok(Some(answer)) if matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes") => None,
+    Ok(_) => Some("declined by the receiver".to_string()),
+    Err(_) => {
+      eprintln!();
+      Some(format!("no answer within {}s", CONFIRM_TIMEOUT.as_secs()))
+    }
+  }
+}
+
+/// Waits for the receiver's answer to `meta`; `Some(reason)` if it declined. Silence for
+/// `limit` counts as declining, so a prompt nobody answers cannot hold the sender and its room
+/// slot forever.
+pub(crate) async fn wait_for_consent(
+  control_rx: &mut mpsc::UnboundedReceiver<DataMessage>,
+  name: &str,
+  limit: Duration,
+) -> Result<Option<String>> {
+  let answer = tokio::time::timeout(limit, async {
+    while let Some(msg) = control_rx.recv().await {
+      match msg {
+        DataMessage::Accept => return Ok(None),
+        DataMessage::Decline { reason } => return Ok(Some(reason.unwrap_or_else(|| "no reason given".to_string()))),
+        other => log_line("[send] ignored", &format!("{other:?} while waiting for an answer to {name}")),
+      }
+    }
+    Err(anyhow!("Channel closed while waiting for the receiver to accept {name}"))
+  })
+  .await;
+  answer.unwrap_or_else(|_| Ok(Some(format!("no answer within {}s", limit.as_secs()))))
+}
+
+/// The reason of a `decline` the receiver sent while the file was streaming, if any. Nothing
+/// else means anything mid-file, so other messages are logged and dropped.
+pub(crate) fn declined_mid_file(control_rx: &mut mpsc::UnboundedReceiver<DataMessage>) -> Option<String> {
+  while let Ok(msg) = control_rx.try_recv() {
+    match msg {
+      DataMessage::Decline { reason } => return Some(reason.unwrap_or_else(|| "no reason given".to_string())),
+      other => log_line("[send] ignored", &format!("{other:?} from the receiver mid-file")),
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn consent_waits_for_an_answer_and_ignores_the_rest() {
+    let (tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    tx.send(DataMessage::Eof).unwrap();
+    tx.send(DataMessage::Accept).unwrap();
+    assert_eq!(wait_for_consent(&mut rx, "a.txt", CONSENT_TIMEOUT).await.unwrap(), None);
+    tx.send(DataMessage::Decline { reason: Some("too big".to_string()) }).unwrap();
+    assert_eq!(
+      wait_for_consent(&mut rx, "a.txt", CONSENT_TIMEOUT).await.unwrap().as_deref(),
+      Some("too big")
+    );
+    drop(tx);
+    assert!(wait_for_consent(&mut rx, "a.txt", CONSENT_TIMEOUT).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn consent_counts_silence_as_declining() {
+    let (_tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    let reason = wait_for_consent(&mut rx, "a.txt", Duration::from_millis(10)).await.unwrap();
+    assert!(reason.is_some_and(|reason| reason.starts_with("no answer")));
+  }
+
+  #[test]
+  fn mid_file_decline_is_found_behind_other_messages() {
+    let (tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    assert_eq!(declined_mid_file(&mut rx), None);
+    tx.send(DataMessage::Accept).unwrap();
+    assert_eq!(declined_mid_file(&mut rx), None);
+    tx.send(DataMessage::Accept).unwrap();
+    tx.send(DataMessage::Decline { reason: None }).unwrap();
+    assert_eq!(declined_mid_file(&mut rx).as_deref(), Some("no reason given"));
+  }
+}
@@ -0,0 +1,231 @@
+//! Everything the CLI reports goes through `emit`, as a log line or as one JSON object per line.
+
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::ReceiverStatus;
+
+const EVENT_SCHEMA_VERSION: u32 = 1;
+
+// `pipe` owns stdout for the byte stream, so its logs go to stderr instead.
+pub(crate) static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+pub(crate) static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// What `--output json` reports, one object per line. Fields are only ever added, and
+/// `version` goes up if one changes meaning. In text mode each event prints its usual log line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub(crate) enum Event<'a> {
+  Room {
+    #[serde(rename = "roomId")]
+    room_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+  },
+  Role {
+    role: &'a str,
+    #[serde(rename = "clientId")]
+    client_id: &'a str,
+  },
+  Peers { count: u32 },
+  Queue {
+    position: Option<u32>,
+    /// The sender left mid-transfer and we are waiting for it to return.
+    requeued: bool,
+  },
+  PeerConnected {
+    #[serde(rename = "peerId")]
+    peer_id: Option<String>,
+  },
+  /// The other end of a `pipe` joined the room.
+  PeerJoined {
+    #[serde(rename = "peerId")]
+    peer_id: &'a str,
+  },
+  /// A `pipe` stream ended: ours once stdin is sent, or the peer's.
+  Eof { direction: Direction },
+  /// The other end of a `pipe` left after its stream ended.
+  PeerLeft,
+  Progress {
+    direction: Direction,
+    name: &'a str,
+    bytes: u64,
+    total: u64,
+  },
+  FileCompleted {
+    direction: Direction,
+    name: &'a str,
+    path: Option<&'a Path>,
+    size: u64,
+    sha256: Option<&'a str>,
+  },
+  Snippet { text: &'a str },
+  /// A `--manual-signaling` blob for the user to pass to the peer.
+  Signal { kind: &'a str, blob: &'a str },
+  /// One line of `room list`.
+  SavedRoom {
+    name: &'a str,
+    #[serde(rename = "roomId")]
+    room_id: &'a str,
+    url: &'a str,
+    #[serde(rename = "createdAt")]
+    created_at: &'a str,
+  },
+  Error {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<&'a str>,
+    message: String,
+    /// Set when the error ends the process.
+    #[serde(rename = "exitCode", skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+  },
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Direction {
+  Send,
+  Receive,
+}
+
+pub(crate) fn log_line(label: &str, value: &str) {
+  let now = chrono::Utc::now().format("%H:%M:%S%.3f");
+  // With JSON events, stdout is reserved for them; under `pipe` stderr is, so text goes nowhere.
+  match (JSON_EVENTS.load(Ordering::Relaxed), LOG_TO_STDERR.load(Ordering::Relaxed)) {
+    (false, false) => println!("[{now}] {label}: {value}"),
+    (true, true) => {}
+    _ => eprintln!("[{now}] {label}: {value}"),
+  }
+}
+
+pub(crate) fn log_error(scope: &str, message: &str) {
+  emit(Event::Error {
+    scope: Some(scope),
+    message: message.to_string(),
+    exit_code: None,
+  });
+}
+
+pub(crate) fn emit(event: Event) {
+  if !JSON_EVENTS.load(Ordering::Relaxed) {
+    event.log();
+    return;
+  }
+  #[derive(Serialize)]
+  struct EventLine<'a> {
+    version: u32,
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+  }
+  let line = EventLine {
+    version: EVENT_SCHEMA_VERSION,
+    time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+    event: &event,
+  };
+  let Ok(json) = serde_json::to_string(&line) else { return };
+  if LOG_TO_STDERR.load(Ordering::Relaxed) {
+    eprintln!("{json}");
+  } else {
+    println!("{json}");
+  }
+}
+
+impl Event<'_> {
+  fn log(&self) {
+    match self {
+      Event::Room { room_id, url } => {
+        log_line("[room] id", room_id);
+        if let Some(url) = url {
+          log_line("[room] url", url);
+        }
+      }
+      Event::Role { role, client_id } => log_line("[ws] role", &format!("{role} ({client_id})")),
+      Event::Peers { count } => log_line("[ws] peers", &count.to_string()),
+      Event::Queue { position, requeued } => {
+        let status = if *requeued {
+          ReceiverStatus::Requeued(*position)
+        } else {
+          ReceiverStatus::Queued(*position)
+        };
+        log_line("[recv] state", &status.describe());
+      }
+      Event::PeerConnected { .. } => log_line("[rtc] connectionState", "Connected"),
+      Event::PeerJoined { peer_id } => log_line("[pipe] peer", peer_id),
+      Event::Eof { direction: Direction::Send } => log_line("[pipe] sent", "eof"),
+      Event::Eof { direction: Direction::Receive } => log_line("[pipe] received", "eof"),
+      Event::PeerLeft => log_line("[pipe] ended", "peer left"),
+      // Text mode has no progress display; the idle timeout is what watches for stalls.
+      Event::Progress { .. } => {}
+      Event::FileCompleted { direction: Direction::Receive, path: Some(path), .. } => {
+        log_line("[recv] completed", &path.display().to_string());
+      }
+      Event::FileCompleted { .. } => {}
+      Event::Snippet { text } => {
+        if text.ends_with('\n') {
+          print!("{text}");
+        } else {
+          println!("{text}");
+        }
+      }
+      Event::Signal { blob, .. } => println!("{blob}"),
+      Event::SavedRoom { name, room_id, url, created_at } => {
+        let created = created_at.get(..10).unwrap_or(created_at);
+        println!("{name}  {room_id}  {created}  {url}");
+      }
+      Event::Error { scope, message, .. } => match scope {
+        Some(scope) => log_line(&format!("[{scope}] error"), message),
+        None => log_line("error", message),
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn json_events_keep_their_schema() {
+    let value = |event: Event| serde_json::to_value(&event).unwrap();
+    assert_eq!(
+      value(Event::Room { room_id: "r1", url: None }),
+      serde_json::json!({"event": "room", "roomId": "r1"})
+    );
+    assert_eq!(
+      value(Event::PeerConnected { peer_id: Some("p1".into()) }),
+      serde_json::json!({"event": "peer-connected", "peerId": "p1"})
+    );
+    assert_eq!(
+      value(Event::FileCompleted {
+        direction: Direction::Receive,
+        name: "a.txt",
+        path: Some(Path::new("out/a.txt")),
+        size: 3,
+        sha256: None,
+      }),
+      serde_json::json!({
+        "event": "file-completed",
+        "direction": "receive",
+        "name": "a.txt",
+        "path": "out/a.txt",
+        "size": 3,
+        "sha256": null,
+      })
+    );
+    assert_eq!(
+      value(Event::Error { scope: None, message: "boom".into(), exit_code: Some(4) }),
+      serde_json::json!({"event": "error", "message": "boom", "exitCode": 4})
+    );
+    assert_eq!(
+      value(Event::Queue { position: None, requeued: true }),
+      serde_json::json!({"event": "queue", "position": null, "requeued": true})
+    );
+    assert_eq!(
+      value(Event::Eof { direction: Direction::Send }),
+      serde_json::json!({"event": "eof", "direction": "send"})
+    );
+    assert_eq!(value(Event::PeerLeft), serde_json::json!({"event": "peer-left"}));
+  }
+}
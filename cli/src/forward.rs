@@ -0,0 +1,361 @@
+//! `pairlane forward`: TCP port forwarding over a pair room, one data channel per connection.
+
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex};
+use uuid::Uuid;
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::events::{emit, log_error, log_line, Event};
+use crate::pair_room::{
+  channel_events, drive_pair_room, open_pair_room, send_stream_frame, wait_channel_open,
+  ChannelEvent, PairRoom, PairRoomHooks,
+};
+use crate::{
+  build_crypto, build_room_url_with_key, build_ws_url, decrypt_frame, frame_chunk_size,
+  open_answerer_peer, open_offerer_peer, send_offer, wait_for_drain, Activity, CliError,
+  DataMessage, DEFAULT_FRAME_SIZE, ForwardArgs, OffererPeer, RoomInput,
+};
+
+const FORWARD_CONTROL_LABEL: &str = "forward";
+const FORWARD_TARGET_PREFIX: &str = "forward:";
+
+#[derive(Clone)]
+struct ForwardContext {
+  crypto: Option<Arc<Aes256Gcm>>,
+  /// Targets the peer may open; `None` on the `--local` side, which serves nothing.
+  allow: Option<Arc<HashSet<String>>>,
+  ready_tx: Arc<watch::Sender<Option<Arc<RTCPeerConnection>>>>,
+}
+
+pub(crate) async fn run_forward(args: ForwardArgs) -> Result<()> {
+  let allow = if args.serve {
+    if args.allow.is_empty() {
+      return Err(anyhow!("--serve needs at least one --allow HOST:PORT"));
+    }
+    let allow = args
+      .allow
+      .iter()
+      .map(|target| normalize_forward_target(target))
+      .collect::<Result<HashSet<_>>>()?;
+    Some(Arc::new(allow))
+  } else {
+    None
+  };
+  let forward = match (args.local.as_deref(), args.remote.as_deref()) {
+    (Some(local), Some(remote)) => {
+      let listener = TcpListener::bind(local).await.with_context(|| format!("listen on {local}"))?;
+      Some((listener, normalize_forward_target(remote)?))
+    }
+    _ => None,
+  };
+
+  let proxy = args.proxy.as_deref();
+  let ice = Arc::new(args.ice);
+  let client_id = Uuid::new_v4().to_string();
+  let RoomInput { room_id, endpoint: endpoint_override, key: room_key } = open_pair_room(
+    args.room_input.as_deref(),
+    args.key.as_deref(),
+    args.no_encrypt,
+    args.endpoint,
+    proxy,
+    &client_id,
+  )
+  .await?;
+  let crypto = match room_key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+  let ws_url = build_ws_url(endpoint_override.as_deref(), &room_id, &client_id)?;
+
+  let room_url = build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
+  });
+
+  let (ready_tx, ready_rx) = watch::channel::<Option<Arc<RTCPeerConnection>>>(None);
+  let ctx = ForwardContext {
+    crypto: crypto.clone(),
+    allow,
+    ready_tx: Arc::new(ready_tx),
+  };
+  let listener_task = forward.map(|(listener, target)| {
+    let local = listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    log_line("[forward] listening", &format!("{local} -> {target} (via peer)"));
+    tokio::spawn(accept_forward_connections(listener, target, ready_rx, crypto.clone()))
+  });
+
+  let mut hooks = ForwardHooks { ctx };
+  let result = drive_pair_room(&ws_url, proxy, ice, &mut hooks).await;
+  if let Some(task) = listener_task {
+    task.abort();
+  }
+  result
+}
+
+/// `forward` in a pair room. The serving side outlives its peers and waits for the next one;
+/// the listening side is useless without a peer, so it stops.
+struct ForwardHooks {
+  ctx: ForwardContext,
+}
+
+impl PairRoomHooks for ForwardHooks {
+  async fn start_offerer(&mut self, room: &PairRoom, peer_id: String) -> Result<Arc<OffererPeer>> {
+    log_line("[forward] peer", &peer_id);
+    let (offerer, dc) =
+      open_offerer_peer(peer_id, FORWARD_CONTROL_LABEL, room.signal_tx.clone(), room.ice.clone()).await?;
+    wire_forward_peer(&offerer.pc, self.ctx.clone());
+    wire_forward_control(&offerer.pc, &dc, self.ctx.ready_tx.clone());
+    send_offer(offerer.clone(), room.signal_tx.clone()).await?;
+    Ok(offerer)
+  }
+
+  async fn start_answerer(&mut self, room: &PairRoom) -> Result<()> {
+    let activity = Arc::new(Mutex::new(Activity::new()));
+    let pc = open_answerer_peer(room.signal_tx.clone(), room.receiver_state.clone(), activity, room.ice.clone()).await?;
+    wire_forward_peer(&pc, self.ctx.clone());
+    Ok(())
+  }
+
+  async fn peer_gone(&mut self) -> Option<Result<()>> {
+    self.ctx.ready_tx.send_replace(None);
+    if self.ctx.allow.is_none() {
+      return Some(Err(CliError::PeerLost("Peer left".to_string()).into()));
+    }
+    log_line("[forward] peer left", "waiting for the next one");
+    None
+  }
+}
+
+/// Checks a `HOST:PORT` target and lowercases the host so allowlist lookups ignore case.
+fn normalize_forward_target(value: &str) -> Result<String> {
+  let (host, port) = value
+    .rsplit_once(':')
+    .ok_or_else(|| anyhow!("Invalid target '{value}' (expected HOST:PORT)"))?;
+  let port: u16 = port
+    .parse()
+    .map_err(|_| anyhow!("Invalid port in '{value}' (expected HOST:PORT)"))?;
+  if host.is_empty() {
+    return Err(anyhow!("Invalid target '{value}' (expected HOST:PORT)"));
+  }
+  Ok(format!("{}:{port}", host.to_ascii_lowercase()))
+}
+
+fn wire_forward_peer(pc: &Arc<RTCPeerConnection>, ctx: ForwardContext) {
+  let weak_pc = Arc::downgrade(pc);
+  pc.on_data_channel(Box::new(move |dc| {
+    let ctx = ctx.clone();
+    let weak_pc = weak_pc.clone();
+    Box::pin(async move {
+      let label = dc.label().to_string();
+      if label == FORWARD_CONTROL_LABEL {
+        if let Some(pc) = weak_pc.upgrade() {
+          wire_forward_control(&pc, &dc, ctx.ready_tx.clone());
+        }
+        return;
+      }
+      match allowed_forward_target(&label, ctx.allow.as_deref()) {
+        Ok(target) => {
+          log_line("[forward] connect", &target);
+          let connect_target = target.clone();
+          bridge_tcp(dc, ctx.crypto.clone(), target, async move {
+            TcpStream::connect(&connect_target)
+              .await
+              .with_context(|| format!("connect {connect_target}"))
+          });
+        }
+        Err(err) => {
+          log_line("[forward] denied", &err.to_string());
+          close_on_open(dc);
+        }
+      }
+    })
+  }));
+}
+
+/// The target a channel from the peer asks to reach, if it is one `--allow` lets through. This
+/// is the only thing standing between the peer and the serving machine's network.
+fn allowed_forward_target(label: &str, allow: Option<&HashSet<String>>) -> Result<String> {
+  let target = label
+    .strip_prefix(FORWARD_TARGET_PREFIX)
+    .ok_or_else(|| anyhow!("unexpected '{label}' channel from peer"))?;
+  let allow = allow.ok_or_else(|| anyhow!("{target}: this side does not --serve"))?;
+  match normalize_forward_target(target) {
+    Ok(target) if allow.contains(&target) => Ok(target),
+    _ => Err(anyhow!("{target} is not in --allow")),
+  }
+}
+
+/// Refuses a channel from the peer. Closing it only works once it is open, which for a
+/// channel the peer created happens right after `on_data_channel` returns.
+fn close_on_open(dc: Arc<RTCDataChannel>) {
+  let open_dc = dc.clone();
+  dc.on_open(Box::new(move || {
+    Box::pin(async move {
+      let _ = open_dc.close().await;
+    })
+  }));
+}
+
+/// The control channel carries no data; it only establishes the connection and tells the
+/// listening side when it can start opening per-connection channels.
+fn wire_forward_control(
+  pc: &Arc<RTCPeerConnection>,
+  dc: &RTCDataChannel,
+  ready_tx: Arc<watch::Sender<Option<Arc<RTCPeerConnection>>>>,
+) {
+  let weak_pc = Arc::downgrade(pc);
+  dc.on_open(Box::new(move || {
+    Box::pin(async move {
+      log_line("[forward] connected", "peer ready");
+      ready_tx.send_replace(weak_pc.upgrade());
+    })
+  }));
+}
+
+async fn accept_forward_connections(
+  listener: TcpListener,
+  target: String,
+  mut ready_rx: watch::Receiver<Option<Arc<RTCPeerConnection>>>,
+  crypto: Option<Arc<Aes256Gcm>>,
+) {
+  let label = format!("{FORWARD_TARGET_PREFIX}{target}");
+  loop {
+    let (stream, addr) = match listener.accept().await {
+      Ok(accepted) => accepted,
+      Err(err) => {
+        log_error("forward", &format!("accept: {err}"));
+        continue;
+      }
+    };
+    // Connections accepted before the peer is connected wait for it rather than being refused.
+    let pc = match ready_rx.wait_for(Option::is_some).await {
+      Ok(pc) => pc.clone(),
+      Err(_) => return,
+    };
+    let Some(pc) = pc else { continue };
+    let dc = match pc
+      .create_data_channel(
+        &label,
+        Some(RTCDataChannelInit {
+          ordered: Some(true),
+          ..Default::default()
+        }),
+      )
+      .await
+    {
+      Ok(dc) => dc,
+      Err(err) => {
+        log_error("forward", &format!("open channel: {err}"));
+        continue;
+      }
+    };
+    log_line("[forward] connection", &format!("{addr} -> {target}"));
+    bridge_tcp(dc, crypto.clone(), addr.to_string(), async move { Ok(stream) });
+  }
+}
+
+/// Relays a forwarded TCP connection over `dc` once the channel opens. `eof` half-closes the
+/// socket the way a FIN would, and closing the channel drops the connection on both ends.
+fn bridge_tcp<F>(dc: Arc<RTCDataChannel>, crypto: Option<Arc<Aes256Gcm>>, name: String, connect: F)
+where
+  F: std::future::Future<Output = Result<TcpStream>> + Send + 'static,
+{
+  let mut events = channel_events(&dc);
+  tokio::spawn(async move {
+    if let Err(err) = relay_tcp(&dc, crypto, connect, &mut events).await {
+      log_error("forward", &format!("{name}: {err:#}"));
+    } else {
+      log_line("[forward] closed", &name);
+    }
+    let _ = dc.close().await;
+  });
+}
+
+async fn relay_tcp<F>(
+  dc: &RTCDataChannel,
+  crypto: Option<Arc<Aes256Gcm>>,
+  connect: F,
+  events: &mut mpsc::Receiver<ChannelEvent>,
+) -> Result<()>
+where
+  F: std::future::Future<Output = Result<TcpStream>>,
+{
+  // Closing the channel (say, because the target refused) only reaches the peer once it is open.
+  if wait_channel_open(events).await.is_err() {
+    return Ok(());
+  }
+  let stream = connect.await?;
+  let (mut read_half, mut write_half) = stream.into_split();
+  let chunk_size = frame_chunk_size(DEFAULT_FRAME_SIZE, crypto.is_some());
+  let upstream = async {
+    let mut buffer = vec![0u8; chunk_size];
+    loop {
+      let read = read_half.read(&mut buffer).await?;
+      if read == 0 {
+        break;
+      }
+      send_stream_frame(dc, crypto.as_deref(), buffer[..read].to_vec()).await?;
+    }
+    dc.send_text(serde_json::to_string(&DataMessage::Eof)?).await?;
+    Ok::<(), anyhow::Error>(())
+  };
+  tokio::pin!(upstream);
+  let mut sent_eof = false;
+  let mut received_eof = false;
+  while !(sent_eof && received_eof) {
+    tokio::select! {
+      result = &mut upstream, if !sent_eof => {
+        result?;
+        sent_eof = true;
+      }
+      event = events.recv() => match event {
+        Some(ChannelEvent::Binary(data)) => {
+          let plain = match crypto.as_ref() {
+            Some(crypto) => decrypt_frame(crypto, &data).context("Decrypt failed (wrong key?)")?,
+            None => data.to_vec(),
+          };
+          write_half.write_all(&plain).await?;
+        }
+        Some(ChannelEvent::Text(text)) => {
+          if let Ok(DataMessage::Eof) = serde_json::from_slice::<DataMessage>(&text) {
+            let _ = write_half.shutdown().await;
+            received_eof = true;
+          }
+        }
+        Some(ChannelEvent::Open) => {}
+        Some(ChannelEvent::Closed) | None => return Ok(()),
+      },
+    }
+  }
+  wait_for_drain(dc).await;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn forward_channels_reach_only_allowed_targets() {
+    let allow: HashSet<String> = ["db.internal:5432".to_string(), "127.0.0.1:22".to_string()].into();
+    assert_eq!(
+      allowed_forward_target("forward:DB.internal:5432", Some(&allow)).unwrap(),
+      "db.internal:5432"
+    );
+    assert_eq!(allowed_forward_target("forward:127.0.0.1:22", Some(&allow)).unwrap(), "127.0.0.1:22");
+    assert!(allowed_forward_target("forward:db.internal:5433", Some(&allow)).is_err());
+    assert!(allowed_forward_target("forward:169.254.169.254:80", Some(&allow)).is_err());
+    assert!(allowed_forward_target("forward:127.0.0.1", Some(&allow)).is_err());
+    assert!(allowed_forward_target("127.0.0.1:22", Some(&allow)).is_err());
+    assert!(allowed_forward_target("file", Some(&allow)).is_err());
+    assert!(allowed_forward_target("forward:127.0.0.1:22", None).is_err());
+  }
+}
@@ -0,0 +1,117 @@
+//! `--exec`: a shell command run after each file that arrives complete.
+
+use anyhow::{Context, Result};
+use std::path::PathBuf;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::events::{log_error, log_line};
+use crate::{hash_file, TransferRecord, TransferStatus};
+
+/// A completed file, handed to the `--exec` hook runner.
+pub(crate) struct ReceivedFile {
+  pub(crate) path: PathBuf,
+  pub(crate) name: String,
+  pub(crate) mime: String,
+  pub(crate) size: u64,
+  pub(crate) sha256: Option<String>,
+  pub(crate) peer_id: Option<String>,
+  pub(crate) sender_name: Option<String>,
+}
+
+/// Runs `--exec` hooks one at a time, in the order files completed. The task ends once every
+/// sender has been dropped and the queue is empty.
+pub(crate) fn spawn_exec_hooks(
+  command: String,
+  journal_tx: Option<mpsc::UnboundedSender<TransferRecord>>,
+) -> (mpsc::UnboundedSender<ReceivedFile>, JoinHandle<()>) {
+  let (exec_tx, mut exec_rx) = mpsc::unbounded_channel::<ReceivedFile>();
+  let handle = tokio::spawn(async move {
+    while let Some(file) = exec_rx.recv().await {
+      let status = match run_exec_hook(&command, &file).await {
+        Ok(status) => {
+          log_line("[exec] finished", &format!("{} ({})", status, file.path.display()));
+          status.code()
+        }
+        Err(err) => {
+          log_error("exec", &format!("{}: {err:#}", file.path.display()));
+          None
+        }
+      };
+      if let Some(tx) = journal_tx.as_ref() {
+        let _ = tx.send(TransferRecord {
+          status: TransferStatus::Exec(status),
+          sender: file.sender_name.clone().or(file.peer_id.clone()).unwrap_or_else(|| "unknown".to_string()),
+          path: file.path,
+          bytes: file.size,
+        });
+      }
+    }
+  });
+  (exec_tx, handle)
+}
+
+async fn run_exec_hook(command: &str, file: &ReceivedFile) -> Result<std::process::ExitStatus> {
+  // Browsers send no digest, so hash the file here rather than leave the variable empty.
+  let sha256 = match file.sha256.clone() {
+    Some(digest) => digest,
+    None => hash_file(&file.path).await?,
+  };
+  #[cfg(windows)]
+  let mut cmd = {
+    let mut cmd = tokio::process::Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+  };
+  #[cfg(not(windows))]
+  let mut cmd = {
+    let mut cmd = tokio::process::Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+  };
+  cmd
+    .env("PAIRLANE_FILE_PATH", &file.path)
+    .env("PAIRLANE_FILE_NAME", &file.name)
+    .env("PAIRLANE_MIME", &file.mime)
+    .env("PAIRLANE_SIZE", file.size.to_string())
+    .env("PAIRLANE_SHA256", sha256)
+    .env("PAIRLANE_PEER_ID", file.peer_id.as_deref().unwrap_or_default())
+    .env("PAIRLANE_SENDER_NAME", file.sender_name.as_deref().unwrap_or_default())
+    .stdin(std::process::Stdio::null());
+  log_line("[exec] run", &file.path.display().to_string());
+  let status = cmd.status().await.context("spawn --exec command")?;
+  Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::TempDir;
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn exec_hook_sees_the_file_in_its_environment() {
+    let dir = TempDir::new();
+    let path = dir.0.join("report.pdf");
+    std::fs::write(&path, b"hello").unwrap();
+    let file = ReceivedFile {
+      path: path.clone(),
+      name: "report.pdf".to_string(),
+      mime: "application/pdf".to_string(),
+      size: 5,
+      sha256: None,
+      peer_id: Some("peer-1".to_string()),
+      sender_name: None,
+    };
+    let command = "printf '%s|%s|%s|%s|%s|%s' \"$PAIRLANE_FILE_NAME\" \"$PAIRLANE_MIME\" \"$PAIRLANE_SIZE\" \
+      \"$PAIRLANE_SHA256\" \"$PAIRLANE_PEER_ID\" \"$PAIRLANE_SENDER_NAME\" > \"$PAIRLANE_FILE_PATH.env\"; exit 3";
+    let status = run_exec_hook(command, &file).await.unwrap();
+    assert_eq!(status.code(), Some(3));
+    // Without a digest from the sender, the hook still gets one.
+    let sha256 = hash_file(&path).await.unwrap();
+    assert_eq!(
+      std::fs::read_to_string(dir.0.join("report.pdf.env")).unwrap(),
+      format!("report.pdf|application/pdf|5|{sha256}|peer-1|")
+    );
+  }
+}
@@ -0,0 +1,311 @@
+//! `pairlane inbox`: a long-lived receiver that keeps its room across restarts and logs every
+//! transfer.
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::events::{emit, log_error, log_line, Event};
+use crate::hooks::spawn_exec_hooks;
+use crate::{
+  b64url_encode, build_crypto, build_room_url_with_key, build_ws_url, connect_signaling, decode_key,
+  generate_key, generate_room_id, handle_receiver_message, parse_room_input, reset_receiver,
+  spawn_signal_writer, split_signaling, write_private_json, Activity, ClientMessage, CliError,
+  InboxArgs, ReceiveProgress, ReceiverSession, ReceiverState, ServerMessage, TransferRecord,
+};
+
+const INBOX_STATE_DIR: &str = ".pairlane";
+const INBOX_LOG_MAX_BYTES: u64 = 1024 * 1024;
+const INBOX_LOG_KEEP: usize = 5;
+const INBOX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InboxState {
+  #[serde(rename = "roomId")]
+  room_id: Option<String>,
+  #[serde(rename = "clientId")]
+  client_id: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  endpoint: Option<String>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  key: Option<String>,
+}
+
+struct InboxLog {
+  path: PathBuf,
+  max_bytes: u64,
+  keep: usize,
+}
+
+pub(crate) async fn run_inbox(args: InboxArgs) -> Result<()> {
+  let state_dir = args.output_dir.join(INBOX_STATE_DIR);
+  tokio::fs::create_dir_all(&state_dir)
+    .await
+    .with_context(|| format!("create {}", state_dir.display()))?;
+  let state_path = state_dir.join("inbox.json");
+  let mut state = InboxState::load(&state_path).await?;
+
+  let key = state.update(args.room.as_deref(), args.endpoint, args.key, args.no_encrypt)?;
+  state.save(&state_path).await?;
+
+  let room_id = state.room_id.clone().unwrap_or_default();
+  let client_id = state.client_id.clone().unwrap_or_default();
+  let endpoint = state.endpoint.as_deref();
+  let crypto = match key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+  let mut ws_url = build_ws_url(endpoint, &room_id, &client_id)?;
+  // Otherwise whoever reaches an empty room first becomes the offerer, including the inbox.
+  ws_url.query_pairs_mut().append_pair("role", "answerer");
+
+  let room_url = build_room_url_with_key(endpoint, &room_id, key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
+  });
+  log_line("[inbox] directory", &args.output_dir.display().to_string());
+
+  let journal = InboxLog {
+    path: args.log_file.unwrap_or_else(|| state_dir.join("inbox.log")),
+    max_bytes: INBOX_LOG_MAX_BYTES,
+    keep: INBOX_LOG_KEEP,
+  };
+  if let Some(parent) = journal.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+    tokio::fs::create_dir_all(parent)
+      .await
+      .with_context(|| format!("create {}", parent.display()))?;
+  }
+  log_line("[inbox] log", &journal.path.display().to_string());
+  let (journal_tx, mut journal_rx) = mpsc::unbounded_channel::<TransferRecord>();
+  tokio::spawn(async move {
+    while let Some(record) = journal_rx.recv().await {
+      if let Err(err) = journal.append(&record).await {
+        log_error("inbox", &format!("{err:#}"));
+      }
+    }
+  });
+
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let mut progress = ReceiveProgress::new(args.output_dir.clone(), crypto, None, activity.clone());
+  progress.saving.inbox_sort = Some(args.sort_by);
+  progress.saving.print_snippets = false;
+  progress.saving.name_template = args.name_template;
+  progress.screening.policy = args.policy;
+  if let Some(command) = args.exec {
+    // The inbox never stops on its own, so the runner simply lives as long as the process.
+    let (exec_tx, _) = spawn_exec_hooks(command, Some(journal_tx.clone()));
+    progress.reports.exec_tx = Some(exec_tx);
+  }
+  progress.reports.journal_tx = Some(journal_tx);
+  let progress = Arc::new(Mutex::new(progress));
+  let receiver_state: Arc<Mutex<Option<ReceiverState>>> = Arc::new(Mutex::new(None));
+  let ice = Arc::new(args.ice);
+  let proxy = args.proxy.as_deref();
+
+  loop {
+    log_line("[ws] connecting", ws_url.as_ref());
+    match connect_signaling(&ws_url, proxy).await {
+      Ok(ws_stream) => {
+        let (ws_write, mut ws_read) = split_signaling(ws_stream);
+        let (signal_tx, signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+        let writer = spawn_signal_writer(ws_write, signal_rx);
+        let session = ReceiverSession {
+          signal_tx,
+          receiver_state: receiver_state.clone(),
+          progress: progress.clone(),
+          activity: activity.clone(),
+          ice: ice.clone(),
+        };
+        while let Some(msg) = ws_read.next().await {
+          let msg = match msg {
+            Ok(msg) => msg,
+            Err(err) => {
+              log_error("ws", &format!("{err:#}"));
+              break;
+            }
+          };
+          let Message::Text(text) = msg else { continue };
+          let parsed: ServerMessage = match serde_json::from_str(&text) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+          };
+          if let ServerMessage::Role { role, .. } = &parsed {
+            check_inbox_role(role)?;
+          }
+          // A single broken transfer must not take the inbox down with it.
+          if let Err(err) = handle_receiver_message(&session, parsed, false).await {
+            log_error("inbox", &format!("{err:#}"));
+            reset_receiver(&receiver_state, &progress).await;
+          }
+        }
+        writer.abort();
+        reset_receiver(&receiver_state, &progress).await;
+        activity.lock().await.receiver = None;
+      }
+      Err(err) => log_error("ws", &format!("{err:#}")),
+    }
+    log_line("[ws] reconnecting", &format!("in {}s", INBOX_RECONNECT_DELAY.as_secs()));
+    sleep(INBOX_RECONNECT_DELAY).await;
+  }
+}
+
+/// An older server ignores `role=answerer` and makes whoever arrives first the offerer.
+fn check_inbox_role(role: &str) -> Result<(), CliError> {
+  if role == "answerer" {
+    Ok(())
+  } else {
+    Err(CliError::Role("The signaling server does not support inbox; it assigned the offerer role"))
+  }
+}
+
+impl InboxState {
+  async fn load(path: &Path) -> Result<Self> {
+    match tokio::fs::read(path).await {
+      Ok(data) => serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display())),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(err) => Err(err).with_context(|| format!("read {}", path.display())),
+    }
+  }
+
+  async fn save(&self, path: &Path) -> Result<()> {
+    write_private_json(path, self).await
+  }
+
+  /// Folds this run's flags into the remembered state and returns the key for this run.
+  fn update(
+    &mut self,
+    room: Option<&str>,
+    endpoint: Option<String>,
+    key: Option<String>,
+    no_encrypt: bool,
+  ) -> Result<Option<Vec<u8>>> {
+    // Everything that identifies the inbox is remembered so a restart reclaims the same room
+    // and the URL teammates bookmarked keeps working.
+    if let Some(room) = room {
+      let parsed = parse_room_input(room)?;
+      if self.room_id.as_deref() != Some(parsed.room_id.as_str()) {
+        self.key = None;
+      }
+      self.room_id = Some(parsed.room_id);
+      if parsed.endpoint.is_some() {
+        self.endpoint = parsed.endpoint;
+      }
+      if let Some(key) = parsed.key {
+        self.key = Some(b64url_encode(&key));
+      }
+    }
+    if endpoint.is_some() {
+      self.endpoint = endpoint;
+    }
+    if self.room_id.is_none() {
+      self.room_id = Some(generate_room_id()?);
+    }
+    if self.client_id.is_none() {
+      self.client_id = Some(Uuid::new_v4().to_string());
+    }
+    if let Some(key) = key {
+      decode_key(&key)?;
+      self.key = Some(key);
+    } else if self.key.is_none() && !no_encrypt {
+      self.key = Some(b64url_encode(&generate_key()?));
+    }
+    // `--no-encrypt` is for this run only: the remembered key, and with it the bookmarked URL,
+    // is back on the next one.
+    if no_encrypt {
+      return Ok(None);
+    }
+    Ok(self.key.as_deref().map(decode_key).transpose()?)
+  }
+}
+
+impl InboxLog {
+  async fn append(&self, record: &TransferRecord) -> Result<()> {
+    self.rotate().await?;
+    let line = format!(
+      "{}\t{}\t{}\t{}\t{}\n",
+      chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%:z"),
+      record.status.label(),
+      record.sender,
+      record.bytes,
+      record.path.display()
+    );
+    let mut file = tokio::fs::OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(&self.path)
+      .await
+      .with_context(|| format!("open {}", self.path.display()))?;
+    file.write_all(line.as_bytes()).await?;
+    Ok(())
+  }
+
+  async fn rotate(&self) -> Result<()> {
+    let size = match tokio::fs::metadata(&self.path).await {
+      Ok(metadata) => metadata.len(),
+      Err(_) => return Ok(()),
+    };
+    if size < self.max_bytes {
+      return Ok(());
+    }
+    // inbox.log -> inbox.log.1 -> ... -> inbox.log.<keep>; the oldest file is overwritten.
+    for index in (1..self.keep).rev() {
+      let from = self.rotated(index);
+      if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+        tokio::fs::rename(&from, self.rotated(index + 1)).await?;
+      }
+    }
+    tokio::fs::rename(&self.path, self.rotated(1)).await?;
+    Ok(())
+  }
+
+  fn rotated(&self, index: usize) -> PathBuf {
+    let mut name = self.path.clone().into_os_string();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn inbox_keeps_its_key_across_a_no_encrypt_run() {
+    let mut state = InboxState::default();
+    let key = state.update(Some("MYTEAM"), None, None, false).unwrap();
+    let saved = state.key.clone();
+    assert!(key.is_some() && saved.is_some());
+    assert_eq!(state.update(None, None, None, true).unwrap(), None);
+    assert_eq!(state.key, saved);
+    assert_eq!(state.update(None, None, None, false).unwrap(), key);
+    assert_eq!(state.room_id.as_deref(), Some("MYTEAM"));
+  }
+
+  #[test]
+  fn inbox_forgets_the_key_of_a_different_room() {
+    let mut state = InboxState::default();
+    let key = state.update(Some("MYTEAM"), None, None, false).unwrap();
+    let other = state.update(Some("OTHER"), None, None, false).unwrap();
+    assert!(other.is_some());
+    assert_ne!(other, key);
+    let mut plain = InboxState::default();
+    assert_eq!(plain.update(Some("MYTEAM"), None, None, true).unwrap(), None);
+    assert_eq!(plain.key, None);
+  }
+
+  #[test]
+  fn inbox_rejects_the_offerer_role() {
+    assert!(check_inbox_role("answerer").is_ok());
+    let err = check_inbox_role("offerer").unwrap_err();
+    assert!(err.to_string().contains("does not support inbox"));
+    assert_eq!(err.exit_code(), 6);
+  }
+}
@@ -5,20 +5,28 @@ use aes_gcm::{Aes256Gcm, Nonce};
 use anyhow::{anyhow, Context, Result};
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use bytes::Bytes;
-use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use futures_util::stream::SplitSink;
+use futures_util::{SinkExt, Stream, StreamExt};
 use getrandom::getrandom;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::{Instant, SystemTime};
 use tokio::fs::File;
+use tokio::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::{mpsc, Mutex};
-use tokio::time::{sleep, Duration};
-use tokio_tungstenite::connect_async;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, Duration};
+use tokio_tungstenite::{client_async_tls, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
 use url::form_urlencoded;
 use url::Url;
 use uuid::Uuid;
@@ -29,60 +37,457 @@ use webrtc::api::APIBuilder;
 use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
 use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
+mod consent;
+mod events;
+mod forward;
+mod hooks;
+mod inbox;
+mod manual;
+mod pair_room;
+mod pipe;
+mod proxy;
+mod qr;
+mod session;
+mod sync;
+
+use consent::{
+  decline, declined_mid_file, prompt_available, send_control, spawn_confirm, wait_for_consent,
+  CONSENT_TIMEOUT, MetaOffer,
+};
+use events::{emit, log_error, log_line, Direction, Event, JSON_EVENTS};
+use forward::run_forward;
+use hooks::{spawn_exec_hooks, ReceivedFile};
+use inbox::run_inbox;
+use manual::{run_receive_manual, run_send_manual};
+use pipe::run_pipe;
+use proxy::{connect_via_http_proxy, connect_via_socks5, proxy_authority, resolve_proxy};
+use qr::{show_room_qr, QrImageFormat};
+use session::run_session;
+use sync::run_sync;
+
 const AES_KEY_LEN: usize = 32;
 const AES_NONCE_LEN: usize = 12;
 const AES_TAG_LEN: usize = 16;
-const MAX_FRAME_SIZE: usize = 16 * 1024;
-
+// What peers that never said hello as a CLI are sent, whatever they advertise; every browser
+// takes this.
+const DEFAULT_FRAME_SIZE: usize = 16 * 1024;
+// The webrtc crate reads data channel messages into a 64 KiB - 1 buffer, and its SCTP stack sends
+// at most 64 KiB, so no peer gets larger frames whatever it advertises.
+const MAX_FRAME_SIZE: usize = u16::MAX as usize;
+const DEFAULT_STUN_URL: &str = "stun:stun.cloudflare.com:3478";
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+// Silence for this long means a keepalive ping went unanswered.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const PROTOCOL_VERSION: u32 = 1;
+const HELLO_TIMEOUT: Duration = Duration::from_millis(1500);
+const FEATURE_SHA256: &str = "sha256";
+const FEATURE_CONSENT: &str = "consent";
+const SUPPORTED_FEATURES: &[&str] = &[FEATURE_SHA256, FEATURE_CONSENT];
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WATCH_SETTLE_TIME: Duration = Duration::from_secs(2);
+const SNIPPET_PRINT_LIMIT: u64 = 64 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+static PROFILE: OnceLock<Profile> = OnceLock::new();
 // Design: see README.md and docs/signaling-protocol.md; related to Command and transfer helpers below.
 #[derive(Parser, Debug)]
 #[command(name = "pairlane")]
 #[command(about = "P2P file transfer CLI for Pairlane")]
 struct Cli {
+  #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text, help = "Log format (json: one event object per line on stdout)")]
+  output: OutputFormat,
+  #[arg(long, global = true, value_name = "NAME", help = "Use [profile.NAME] from ~/.config/pairlane/config.toml (default: [profile.default])")]
+  profile: Option<String>,
   #[command(subcommand)]
   command: Command,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+  /// Timestamped log lines for people
+  Text,
+  /// JSON Lines events for scripts; text logs move to stderr
+  Json,
+}
+
 #[derive(Subcommand, Debug)]
 enum Command {
-  Send {
-    #[arg(value_name = "FILE", help = "File to send")]
-    file: Option<PathBuf>,
-    #[arg(long = "file", value_name = "PATH", help = "File to send (legacy --file)", hide = true)]
-    file_flag: Option<PathBuf>,
-    #[arg(value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (optional, supports #k=...)")]
-    room_input: Option<String>,
-    #[arg(long = "room-id", value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (legacy --room-id)", hide = true)]
-    room_id: Option<String>,
-    #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
-    endpoint: Option<String>,
-    #[arg(long, help = "Disable E2E encryption (default: enabled)")]
-    no_encrypt: bool,
-    #[arg(long, help = "Keep running after a successful send")]
-    stay_open: bool,
-  },
-  Receive {
-    #[arg(value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (supports #k=...)")]
-    room_input: Option<String>,
-    #[arg(long = "room-id", value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (legacy --room-id)", hide = true)]
-    room_id: Option<String>,
-    #[arg(long, default_value = ".", value_name = "DIR", help = "Output directory")]
-    output_dir: PathBuf,
-    #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
-    endpoint: Option<String>,
-    #[arg(long, value_name = "KEY", help = "Base64url decryption key (overrides #k=...)")]
-    key: Option<String>,
-    #[arg(long, help = "Keep running after a successful receive")]
-    stay_open: bool,
-  },
+  Send(SendArgs),
+  Receive(ReceiveArgs),
+  Inbox(InboxArgs),
+  Session(SessionArgs),
+  Pipe(PipeArgs),
+  Forward(ForwardArgs),
+  Sync(SyncArgs),
+  #[command(subcommand)]
+  Room(RoomCommand),
+}
+
+#[derive(Args, Debug)]
+struct SendArgs {
+  #[arg(value_name = "FILE", help = "File to send")]
+  file: Option<PathBuf>,
+  #[arg(long = "file", value_name = "PATH", help = "File to send (legacy --file)", hide = true)]
+  file_flag: Option<PathBuf>,
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (optional, supports #k=...)")]
+  room_input: Option<String>,
+  #[arg(long = "room-id", value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (legacy --room-id)", hide = true)]
+  room_id: Option<String>,
+  #[arg(long, value_name = "NAME", conflicts_with_all = ["room_input", "room_id", "max_concurrent"], help = "Send in a room saved with `room create --name NAME`")]
+  room: Option<String>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, help = "Disable E2E encryption (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[arg(long, help = "Keep running after a successful send")]
+  stay_open: bool,
+  #[arg(long, value_name = "NAME", help = "Name announced to the receiver (used by `inbox --sort-by sender`)")]
+  sender_name: Option<String>,
+  #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=10), help = "Receivers served at once when creating a room (server default: 3)")]
+  max_concurrent: Option<u32>,
+  #[arg(long, value_name = "N", conflicts_with = "stay_open", value_parser = clap::value_parser!(u32).range(1..), help = "Exit after N distinct receivers finish (default: 1)")]
+  max_receivers: Option<u32>,
+  #[arg(long, value_name = "N", conflicts_with_all = ["stay_open", "max_receivers", "watch"], value_parser = clap::value_parser!(u32).range(1..), help = "Serve at most N receivers, turning away later ones, then exit")]
+  max_downloads: Option<u32>,
+  #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Serve receivers until this much time has passed, then exit (e.g. 2h)")]
+  expires: Option<Duration>,
+  #[arg(long, value_name = "DIR", conflicts_with_all = ["file_flag", "stay_open", "max_receivers"], help = "Keep the room open and send every new or modified file in DIR to all receivers")]
+  watch: Option<PathBuf>,
+  #[arg(long, value_name = "TEXT", conflicts_with_all = ["file_flag", "watch"], help = "Send a text snippet instead of a file (`-` reads stdin)")]
+  text: Option<String>,
+  #[arg(long, conflicts_with_all = ["room_input", "room_id", "room", "stay_open", "max_concurrent", "max_receivers", "max_downloads", "expires", "watch"], help = "Exchange copy-paste offer/answer blobs instead of using the signaling server; the offer carries the encryption key, so keep it secret")]
+  manual_signaling: bool,
+  #[arg(long, conflicts_with_all = ["no_qr", "manual_signaling"], help = "Print the room URL as a QR code (default: when stdout is a terminal)")]
+  qr: bool,
+  #[arg(long, help = "Never print the room URL as a QR code")]
+  no_qr: bool,
+  #[arg(long, value_name = "PATH", conflicts_with = "manual_signaling", help = "Write the room URL as a QR code image (.png or .svg)")]
+  qr_file: Option<PathBuf>,
+  #[command(flatten)]
+  ice: IceArgs,
+  #[command(flatten)]
+  timeouts: TimeoutArgs,
+}
+
+#[derive(Args, Debug)]
+struct ReceiveArgs {
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (supports #k=...)")]
+  room_input: Option<String>,
+  #[arg(long = "room-id", value_name = "ROOM_ID_OR_URL", help = "Room ID or full room URL (legacy --room-id)", hide = true)]
+  room_id: Option<String>,
+  #[arg(long, default_value = ".", value_name = "DIR", help = "Output directory")]
+  output_dir: PathBuf,
+  #[arg(long = "output-file", short = 'o', value_name = "PATH", conflicts_with_all = ["output_dir", "name_template", "stay_open"], help = "Write the file to exactly this path, whatever the sender calls it")]
+  output_file: Option<PathBuf>,
+  #[arg(long, value_name = "TEMPLATE", value_parser = parse_name_template, help = "Name saved files from {name}, {stem}, {ext}, {date}, {time} and {sender} (e.g. '{date}-{name}')")]
+  name_template: Option<String>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url decryption key (overrides #k=...)")]
+  key: Option<String>,
+  #[arg(long, help = "Keep running after a successful receive")]
+  stay_open: bool,
+  #[arg(long, help = "Fail instead of waiting for the sender to return when it disconnects")]
+  fail_on_sender_loss: bool,
+  #[arg(long, value_name = "CMD", help = "Shell command to run after each completed file (details in PAIRLANE_* variables)")]
+  exec: Option<String>,
+  #[arg(long, short = 'y', help = "Accept every file without asking (default: ask when attached to a terminal)")]
+  yes: bool,
+  #[arg(long, conflicts_with_all = ["room_input", "room_id", "stay_open"], help = "Exchange copy-paste offer/answer blobs instead of using the signaling server; the offer carries the encryption key, so keep it secret")]
+  manual_signaling: bool,
+  #[command(flatten)]
+  policy: PolicyArgs,
+  #[command(flatten)]
+  ice: IceArgs,
+  #[command(flatten)]
+  timeouts: TimeoutArgs,
+}
+
+#[derive(Args, Debug)]
+struct InboxArgs {
+  #[arg(long, value_name = "ROOM_ID_OR_URL", help = "Room to listen on (default: the room remembered in the output directory)")]
+  room: Option<String>,
+  #[arg(long, default_value = ".", value_name = "DIR", help = "Output directory")]
+  output_dir: PathBuf,
+  #[arg(long, value_enum, default_value_t = InboxSort::Flat, help = "Sort received files into subfolders")]
+  sort_by: InboxSort,
+  #[arg(long, value_name = "TEMPLATE", value_parser = parse_name_template, help = "Name saved files from {name}, {stem}, {ext}, {date}, {time} and {sender} (e.g. '{date}-{name}')")]
+  name_template: Option<String>,
+  #[arg(long, value_name = "PATH", help = "Transfer log, rotated at 1 MiB (default: <DIR>/.pairlane/inbox.log)")]
+  log_file: Option<PathBuf>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url decryption key (overrides #k=... and the remembered key)")]
+  key: Option<String>,
+  #[arg(long, conflicts_with = "key", help = "Accept unencrypted transfers only (no key in the room URL)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[arg(long, value_name = "CMD", help = "Shell command to run after each completed file (details in PAIRLANE_* variables)")]
+  exec: Option<String>,
+  #[command(flatten)]
+  policy: PolicyArgs,
+  #[command(flatten)]
+  ice: IceArgs,
+}
+
+#[derive(Args, Debug)]
+struct SessionArgs {
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room to join (default: create a new room)")]
+  room_input: Option<String>,
+  #[arg(long = "send", value_name = "FILE", help = "File to send once connected, repeatable (more paths can be typed on stdin)")]
+  files: Vec<PathBuf>,
+  #[arg(long, default_value = ".", value_name = "DIR", help = "Output directory")]
+  output_dir: PathBuf,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url encryption key (overrides #k=...)")]
+  key: Option<String>,
+  #[arg(long, conflicts_with_all = ["room_input", "key"], help = "Disable E2E encryption when creating the room (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[arg(long, value_name = "NAME", help = "Name announced to the peer")]
+  sender_name: Option<String>,
+  #[command(flatten)]
+  ice: IceArgs,
+}
+
+#[derive(Args, Debug)]
+struct PipeArgs {
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room to join (default: create a new room)")]
+  room_input: Option<String>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url encryption key (overrides #k=...)")]
+  key: Option<String>,
+  #[arg(long, conflicts_with_all = ["room_input", "key"], help = "Disable E2E encryption when creating the room (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[command(flatten)]
+  ice: IceArgs,
+}
+
+#[derive(Args, Debug)]
+struct ForwardArgs {
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room to join (default: create a new room)")]
+  room_input: Option<String>,
+  #[arg(long, value_name = "ADDR", requires = "remote", conflicts_with = "serve", help = "Local address to listen on, e.g. 127.0.0.1:5432")]
+  local: Option<String>,
+  #[arg(long, value_name = "HOST:PORT", requires = "local", help = "Address the serving peer connects each forwarded connection to")]
+  remote: Option<String>,
+  #[arg(long, required_unless_present = "local", help = "Connect forwarded connections to their targets on this machine")]
+  serve: bool,
+  #[arg(long = "allow", value_name = "HOST:PORT", requires = "serve", help = "Target the peer may reach through --serve, repeatable")]
+  allow: Vec<String>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url encryption key (overrides #k=...)")]
+  key: Option<String>,
+  #[arg(long, conflicts_with_all = ["room_input", "key"], help = "Disable E2E encryption when creating the room (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[command(flatten)]
+  ice: IceArgs,
+}
+
+#[derive(Args, Debug)]
+struct SyncArgs {
+  #[arg(value_name = "SRC", help = "Directory to send")]
+  source: Option<PathBuf>,
+  #[arg(value_name = "ROOM_ID_OR_URL", help = "Room to join (default: create a new room)")]
+  room_input: Option<String>,
+  #[arg(long, value_name = "DST", help = "Receive into DST, transferring only blocks that differ")]
+  into: Option<PathBuf>,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation and signaling, not peer-to-peer or TURN traffic (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, value_name = "KEY", help = "Base64url encryption key (overrides #k=...)")]
+  key: Option<String>,
+  #[arg(long, conflicts_with_all = ["room_input", "key"], help = "Disable E2E encryption when creating the room (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[command(flatten)]
+  ice: IceArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum RoomCommand {
+  /// Create a room and save it under a name for `send --room`
+  Create(RoomCreateArgs),
+  /// Show the saved rooms
+  List,
+}
+
+#[derive(Args, Debug)]
+struct RoomCreateArgs {
+  #[arg(long, value_name = "NAME", help = "Name to save the room under (letters, digits, '-', '_' and '.')")]
+  name: String,
+  #[arg(long, value_name = "URL", help = "Override signaling endpoint")]
+  endpoint: Option<String>,
+  #[arg(long, value_name = "URL", help = "HTTP or SOCKS5 proxy for room creation (default: HTTPS_PROXY/ALL_PROXY)")]
+  proxy: Option<String>,
+  #[arg(long, help = "Disable E2E encryption (default: enabled)")]
+  no_encrypt: bool,
+  #[arg(long, overrides_with = "no_encrypt", help = "Encrypt even if the profile sets `encrypt = false`")]
+  encrypt: bool,
+  #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=10), help = "Receivers served at once (server default: 3)")]
+  max_concurrent: Option<u32>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum InboxSort {
+  /// Everything directly in the output directory
+  Flat,
+  /// One subfolder per day (YYYY-MM-DD)
+  Date,
+  /// One subfolder per sender name
+  Sender,
+}
+
+#[derive(Args, Debug, Clone)]
+struct IceArgs {
+  #[arg(long = "ice-server", value_name = "URL", help = "STUN/TURN server URL, repeatable (default: Cloudflare STUN)")]
+  ice_servers: Vec<String>,
+  #[arg(long, value_name = "USER", help = "Username for turn:/turns: servers")]
+  turn_username: Option<String>,
+  #[arg(long, value_name = "SECRET", help = "Credential for turn:/turns: servers")]
+  turn_credential: Option<String>,
+  #[arg(long, help = "Only connect through TURN relays (ICE transport policy: relay)")]
+  relay_only: bool,
+  #[arg(long, overrides_with = "relay_only", help = "Allow direct connections even if the profile sets `relay-only`")]
+  no_relay_only: bool,
+  #[arg(long, help = "Never expose host (LAN/VPN) candidates to the peer")]
+  no_host_candidates: bool,
+  #[arg(long, overrides_with = "no_host_candidates", help = "Expose host candidates even if the profile sets `no-host-candidates`")]
+  host_candidates: bool,
+}
+
+#[derive(Args, Debug, Clone, Copy)]
+struct TimeoutArgs {
+  #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Give up if no peer starts a transfer within this time (e.g. 90s, 10m)")]
+  wait_timeout: Option<Duration>,
+  #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Give up if the peer connection does not open within this time")]
+  connect_timeout: Option<Duration>,
+  #[arg(long, value_name = "DURATION", value_parser = parse_duration, help = "Give up if a transfer makes no progress for this long")]
+  idle_timeout: Option<Duration>,
+}
+
+/// What `receive` and `inbox` accept. Checked against each `meta` before anything is written, and
+/// the sender is told why a file was refused.
+#[derive(Args, Debug, Clone, Default)]
+struct PolicyArgs {
+  #[arg(long, value_name = "SIZE", value_parser = parse_size, help = "Refuse files larger than this (e.g. 500M, 2G)")]
+  max_size: Option<u64>,
+  #[arg(long, value_name = "GLOB", help = "Only accept MIME types matching this pattern, repeatable (e.g. 'image/*')")]
+  allow_mime: Vec<String>,
+  #[arg(long, value_name = "GLOB", help = "Refuse MIME types matching this pattern, repeatable (e.g. 'application/x-ms*')")]
+  deny_mime: Vec<String>,
+  #[arg(long, value_name = "EXT", value_delimiter = ',', help = "Only accept these file extensions, repeatable or comma-separated (e.g. pdf,jpg)")]
+  allow_ext: Vec<String>,
+}
+
+/// `~/.config/pairlane/config.toml`. Each `[profile.NAME]` table holds defaults for options that
+/// are tedious to repeat; flags and environment variables still take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+  #[serde(default)]
+  profile: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Profile {
+  endpoint: Option<String>,
+  proxy: Option<String>,
+  ice_servers: Option<Vec<String>>,
+  turn_username: Option<String>,
+  turn_credential: Option<String>,
+  relay_only: Option<bool>,
+  no_host_candidates: Option<bool>,
+  output_dir: Option<PathBuf>,
+  /// `false` makes `--no-encrypt` the default.
+  encrypt: Option<bool>,
+}
+
+/// Failures with their own exit status (see `exit_code`); anything else exits with 1 and clap's
+/// usage errors with 2.
+#[derive(Debug)]
+enum CliError {
+  WaitTimeout(Duration),
+  ConnectTimeout(Duration),
+  IdleTimeout(Duration),
+  /// The server assigned the other side of the room.
+  Role(&'static str),
+  /// A key that is malformed, missing or does not decrypt what the peer sends.
+  Key(String),
+  /// The signaling server could not be reached or dropped the connection.
+  Signaling(String),
+  /// The server answered HTTP 429.
+  RateLimited,
+  /// The peer left or its connection failed before the transfer finished.
+  PeerLost(String),
+  /// Received data does not match the sender's SHA-256.
+  Checksum(String),
+  /// A local file could not be read or written.
+  Storage(String),
+  /// An incoming file broke the `--max-size`/`--allow-*`/`--deny-mime` policy or would not fit on
+  /// disk, or the sender turned us away (`--max-downloads`).
+  Refused(String),
+  /// `receive` declined a file at the prompt, or a plain `send`'s receiver declined it.
+  Declined(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+  Waiting,
+  Connecting,
+  Transferring,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReceiverStatus {
+  Queued(Option<u32>),
+  Connecting,
+  Transferring,
+  SenderGone,
+  Requeued(Option<u32>),
+  Done,
+}
+
+struct Activity {
+  phase: Phase,
+  since: Instant,
+  receiver: Option<ReceiverStatus>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -98,6 +503,8 @@ enum ServerMessage {
   Start { #[serde(rename = "peerId")] peer_id: Option<String> },
   #[serde(rename = "peer-left")]
   PeerLeft { #[serde(rename = "peerId")] peer_id: String },
+  #[serde(rename = "refused")]
+  Refused { reason: Option<String> },
   #[serde(rename = "offer")]
   Offer { from: String, sid: u64, sdp: RTCSessionDescription },
   #[serde(rename = "answer")]
@@ -117,20 +524,51 @@ enum ClientMessage {
   Candidate { to: String, sid: u64, candidate: RTCIceCandidateInit },
   #[serde(rename = "transfer-done")]
   TransferDone { #[serde(rename = "peerId")] peer_id: String },
+  #[serde(rename = "refuse")]
+  Refuse { #[serde(rename = "peerId")] peer_id: String, reason: String },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 enum DataMessage {
+  #[serde(rename = "hello")]
+  Hello {
+    version: u32,
+    #[serde(default)]
+    features: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+  },
   #[serde(rename = "meta")]
   Meta {
     name: String,
     size: u64,
     mime: String,
     encrypted: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    snippet: bool,
   },
   #[serde(rename = "done")]
-  Done,
+  Done {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+  },
+  #[serde(rename = "eof")]
+  Eof,
+  /// With `consent`, the receiver's answer to `meta`; the sender streams nothing before it.
+  #[serde(rename = "accept")]
+  Accept,
+  #[serde(rename = "decline")]
+  Decline {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+  },
+}
+
+#[derive(Debug, Clone, Default)]
+struct Capabilities {
+  version: u32,
+  features: Vec<String>,
 }
 
 struct RoomInput {
@@ -145,6 +583,62 @@ struct FileInfo {
   name: String,
   size: u64,
   mime: String,
+  // Text snippets are sent from memory rather than read from `path`.
+  inline: Option<Bytes>,
+}
+
+#[derive(Clone)]
+enum FileFeed {
+  Single(Arc<FileInfo>),
+  Shared(Arc<Mutex<SharedFeed>>),
+}
+
+// Files published while the room is open (`send --watch`, `session`), replayed to peers that join later.
+#[derive(Default)]
+struct SharedFeed {
+  published: Vec<Arc<FileInfo>>,
+  subscribers: Vec<mpsc::UnboundedSender<Arc<FileInfo>>>,
+}
+
+/// The receivers a `send` has served, for `--max-receivers`, `--max-downloads` and the summary.
+#[derive(Default)]
+struct DownloadTally {
+  /// Finished receivers after which the share ends.
+  limit: Option<usize>,
+  quota: Option<usize>,
+  finished: HashSet<String>,
+  declined: HashSet<String>,
+  /// Each finished receiver with the time it finished, in order.
+  downloads: Vec<(String, String)>,
+}
+
+/// What `send --watch` knows of its directory: files already dealt with, and new or changed
+/// ones waiting for their size and mtime to settle.
+#[derive(Default)]
+struct WatchState {
+  seen: HashMap<PathBuf, (SystemTime, u64)>,
+  pending: HashMap<PathBuf, ((SystemTime, u64), Instant)>,
+}
+
+#[derive(Clone)]
+struct SendContext {
+  signal_tx: mpsc::UnboundedSender<ClientMessage>,
+  files: FileFeed,
+  crypto: Option<Arc<Aes256Gcm>>,
+  success_tx: Option<mpsc::UnboundedSender<String>>,
+  // Receivers that declined a file. They are not counted as downloads; without this channel a
+  // decline ends the run through `failure_tx` instead.
+  declined_tx: Option<mpsc::UnboundedSender<String>>,
+  // Typed errors from the data channel callbacks that should end the run.
+  failure_tx: Option<mpsc::UnboundedSender<CliError>>,
+  ice: Arc<IceArgs>,
+  activity: Arc<Mutex<Activity>>,
+  sender_name: Option<String>,
+  // Set in `session`, where the sender's data channel also carries files from the peer.
+  inbound: Option<Arc<Mutex<ReceiveProgress>>>,
+  // `send --watch` never finishes with a receiver, so it hands back the receiver's room slot
+  // once it has caught up, letting queued receivers in past `maxConcurrent`.
+  release_idle: bool,
 }
 
 struct OffererPeerState {
@@ -153,6 +647,7 @@ struct OffererPeerState {
   pending_candidates: Vec<PendingCandidate>,
   remote_desc_set: bool,
   sending: bool,
+  control_rx: Option<mpsc::UnboundedReceiver<DataMessage>>,
 }
 
 struct PendingCandidate {
@@ -163,11 +658,13 @@ struct PendingCandidate {
 struct OffererPeer {
   peer_id: String,
   pc: Arc<RTCPeerConnection>,
+  ice: Arc<IceArgs>,
   state: Arc<Mutex<OffererPeerState>>,
 }
 
 struct ReceiverState {
   pc: Arc<RTCPeerConnection>,
+  ice: Arc<IceArgs>,
   peer_id: Option<String>,
   active_sid: Option<u64>,
   pending_candidates: Vec<PendingCandidate>,
@@ -175,7 +672,6 @@ struct ReceiverState {
 }
 
 struct ReceiveProgress {
-  output_dir: PathBuf,
   current_file: Option<PathBuf>,
   file: Option<File>,
   expected_size: u64,
@@ -183,67 +679,293 @@ struct ReceiveProgress {
   encrypted: bool,
   crypto: Option<Arc<Aes256Gcm>>,
   success_tx: Option<mpsc::UnboundedSender<()>>,
+  // Set by `receive`, which stops on the first failed file; `inbox` and `session` carry on.
+  failure_tx: Option<mpsc::UnboundedSender<CliError>>,
+  activity: Arc<Mutex<Activity>>,
+  peer_caps: Capabilities,
+  hasher: Option<Sha256>,
+  sender: Option<String>,
+  sender_name: Option<String>,
+  // Small snippets are collected here and printed instead of being written to a file.
+  snippet: Option<Vec<u8>>,
+  // The current file as the sender described it, for `--exec` hooks.
+  file_name: String,
+  mime: String,
+  digest: Option<String>,
+  last_progress: Instant,
+  saving: SaveRules,
+  screening: Screening,
+  reports: TransferReports,
+}
+
+/// Where received files land and what they are called.
+struct SaveRules {
+  output_dir: PathBuf,
+  // Inbox mode only: files are sorted into subfolders and never overwrite each other.
+  inbox_sort: Option<InboxSort>,
+  // `receive -o` writes to this exact path; otherwise the sender's name goes through the
+  // `--name-template`, if any. Logs and events still show the name the sender gave.
+  output_file: Option<PathBuf>,
+  name_template: Option<String>,
+  // Off in `inbox`, which saves every snippet as a file.
+  print_snippets: bool,
+}
+
+/// Which offers are let in before anything is written.
+#[derive(Default)]
+struct Screening {
+  // `receive` on a terminal asks before accepting each file.
+  confirm: bool,
+  policy: PolicyArgs,
+}
+
+/// Who hears about finished and abandoned transfers besides the log.
+#[derive(Default)]
+struct TransferReports {
+  // Inbox mode only: every transfer goes to the inbox log.
+  journal_tx: Option<mpsc::UnboundedSender<TransferRecord>>,
+  // Completed files, for `--exec` hooks.
+  exec_tx: Option<mpsc::UnboundedSender<ReceivedFile>>,
+}
+
+struct ReceiverSession {
+  signal_tx: mpsc::UnboundedSender<ClientMessage>,
+  receiver_state: Arc<Mutex<Option<ReceiverState>>>,
+  progress: Arc<Mutex<ReceiveProgress>>,
+  activity: Arc<Mutex<Activity>>,
+  ice: Arc<IceArgs>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TransferStatus {
+  Completed,
+  Incomplete,
+  ChecksumMismatch,
+  /// Turned down by the receive policy before anything was written.
+  Refused,
+  /// The `--exec` hook's exit code; `None` if it could not run or was killed by a signal.
+  Exec(Option<i32>),
+}
+
+struct TransferRecord {
+  status: TransferStatus,
+  sender: String,
+  path: PathBuf,
+  bytes: u64,
+}
+
+/// `rooms.json` next to `config.toml`: rooms made by `room create`, keyed by name. Keeping the
+/// creator's client ID lets `send --room` claim the offerer role in them again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedRooms {
+  #[serde(default)]
+  rooms: BTreeMap<String, SavedRoom>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SavedRoom {
+  #[serde(rename = "roomId")]
+  room_id: String,
+  #[serde(rename = "creatorCid")]
+  creator_cid: String,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  endpoint: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  key: Option<String>,
+  #[serde(rename = "createdAt")]
+  created_at: String,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-  let cli = Cli::parse();
-
-  match cli.command {
-    Command::Send {
-      file,
-      file_flag,
-      room_input,
-      room_id,
-      endpoint,
-      no_encrypt,
-      stay_open,
-    } => {
-      let file = file_flag
-        .or(file)
-        .ok_or_else(|| anyhow!("File path is required (usage: send <FILE>)"))?;
-      let room_input = room_id.or(room_input);
-      run_send(room_input.as_deref(), &file, endpoint.as_deref(), no_encrypt, stay_open).await
-    }
-    Command::Receive {
-      room_input,
-      room_id,
-      output_dir,
-      endpoint,
-      key,
-      stay_open,
-    } => {
-      let room_input = room_id
-        .or(room_input)
-        .ok_or_else(|| anyhow!("Room ID or URL is required (usage: receive <ROOM_ID_OR_URL>)"))?;
-      run_receive(&room_input, &output_dir, endpoint.as_deref(), key.as_deref(), stay_open).await
-    }
-  }
-}
-
-async fn run_send(
-  room_id: Option<&str>,
-  file_path: &Path,
-  endpoint: Option<&str>,
-  no_encrypt: bool,
-  stay_open: bool,
-) -> Result<()> {
-  let file_info = load_file_info(file_path).await?;
-  let mut endpoint_override = endpoint.map(|value| value.to_string());
+async fn main() {
+  let matches = Cli::command().get_matches();
+  let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+  JSON_EVENTS.store(cli.output == OutputFormat::Json, Ordering::Relaxed);
+
+  let result = async {
+    apply_profile(&mut cli, &matches)?;
+    match cli.command {
+      Command::Send(args) => run_send(args).await,
+      Command::Receive(args) => run_receive(args).await,
+      Command::Inbox(args) => run_inbox(args).await,
+      Command::Session(args) => run_session(args).await,
+      Command::Pipe(args) => run_pipe(args).await,
+      Command::Forward(args) => run_forward(args).await,
+      Command::Sync(args) => run_sync(args).await,
+      Command::Room(RoomCommand::Create(args)) => run_room_create(args).await,
+      Command::Room(RoomCommand::List) => run_room_list().await,
+    }
+  }
+  .await;
+  if let Err(err) = result {
+    let code = exit_code(&err);
+    if JSON_EVENTS.load(Ordering::Relaxed) {
+      emit(Event::Error {
+        scope: None,
+        message: format!("{err:#}"),
+        exit_code: Some(code),
+      });
+    } else {
+      eprintln!("Error: {err:?}");
+    }
+    std::process::exit(code);
+  }
+}
+
+/// Fills in what the command line left unset from the selected profile. The endpoint and proxy
+/// are looked up later, after their environment variables (see `base_endpoint_url`).
+fn apply_profile(cli: &mut Cli, matches: &ArgMatches) -> Result<()> {
+  let profile = load_profile(cli.profile.as_deref())?;
+  fill_from_profile(cli, matches, &profile);
+  let _ = PROFILE.set(profile);
+  Ok(())
+}
+
+fn fill_from_profile(cli: &mut Cli, matches: &ArgMatches, profile: &Profile) {
+  let (ice, output_dir, no_encrypt) = match &mut cli.command {
+    Command::Send(args) => (Some(&mut args.ice), None, Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Receive(args) => (Some(&mut args.ice), Some(&mut args.output_dir), None),
+    Command::Inbox(args) => (Some(&mut args.ice), Some(&mut args.output_dir), Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Session(args) => (Some(&mut args.ice), Some(&mut args.output_dir), Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Pipe(args) => (Some(&mut args.ice), None, Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Forward(args) => (Some(&mut args.ice), None, Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Sync(args) => (Some(&mut args.ice), None, Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Room(RoomCommand::Create(args)) => (None, None, Some((&mut args.no_encrypt, args.encrypt))),
+    Command::Room(RoomCommand::List) => (None, None, None),
+  };
+  if let Some(ice) = ice {
+    if ice.ice_servers.is_empty() {
+      ice.ice_servers = profile.ice_servers.clone().unwrap_or_default();
+    }
+    ice.turn_username = ice.turn_username.take().or_else(|| profile.turn_username.clone());
+    ice.turn_credential = ice.turn_credential.take().or_else(|| profile.turn_credential.clone());
+    // A profile can only switch these on; `--no-relay-only` and `--host-candidates` undo it.
+    ice.relay_only |= !ice.no_relay_only && profile.relay_only.unwrap_or(false);
+    ice.no_host_candidates |= !ice.host_candidates && profile.no_host_candidates.unwrap_or(false);
+  }
+  // `--output-dir` has a default value, so only the parser knows whether it was given.
+  let sub_matches = matches.subcommand().map(|(_, sub_matches)| sub_matches);
+  if let (Some(output_dir), Some(profile_dir)) = (output_dir, profile.output_dir.as_ref()) {
+    if sub_matches.and_then(|sub_matches| sub_matches.value_source("output_dir")) == Some(ValueSource::DefaultValue) {
+      *output_dir = profile_dir.clone();
+    }
+  }
+  if let Some((no_encrypt, encrypt)) = no_encrypt {
+    *no_encrypt |= !encrypt && profile.encrypt == Some(false);
+  }
+}
+
+fn load_profile(name: Option<&str>) -> Result<Profile> {
+  let path = config_path();
+  let text = match path.as_deref().map(std::fs::read_to_string) {
+    Some(Ok(text)) => text,
+    Some(Err(err)) if err.kind() != std::io::ErrorKind::NotFound => {
+      return Err(err).with_context(|| format!("read {}", path.unwrap_or_default().display()));
+    }
+    _ => {
+      return match name {
+        Some(name) => Err(anyhow!(
+          "Profile '{name}' not found: there is no {}",
+          path.unwrap_or_default().display()
+        )),
+        None => Ok(Profile::default()),
+      };
+    }
+  };
+  let path = path.unwrap_or_default();
+  let mut config: Config = toml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+  match name {
+    Some(name) => config.profile.remove(name).ok_or_else(|| {
+      let mut known = config.profile.keys().map(String::as_str).collect::<Vec<_>>();
+      known.sort_unstable();
+      anyhow!(
+        "Profile '{name}' is not defined in {} (available: {})",
+        path.display(),
+        if known.is_empty() { "none".to_string() } else { known.join(", ") }
+      )
+    }),
+    None => Ok(config.profile.remove("default").unwrap_or_default()),
+  }
+}
+
+fn config_path() -> Option<PathBuf> {
+  if let Some(path) = env::var_os("PAIRLANE_CONFIG").filter(|path| !path.is_empty()) {
+    return Some(PathBuf::from(path));
+  }
+  let config_dir = env::var_os("XDG_CONFIG_HOME")
+    .filter(|dir| !dir.is_empty())
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+  Some(config_dir.join("pairlane").join("config.toml"))
+}
+
+fn profile() -> &'static Profile {
+  PROFILE.get_or_init(Profile::default)
+}
+
+async fn run_send(args: SendArgs) -> Result<()> {
+  let mut room_id = args.room_id.or(args.room_input);
+  let mut file_path = args.file_flag.or(args.file);
+  let fileless = args.watch.is_some() || args.text.is_some();
+  // Without a FILE, clap parses a lone ROOM positional as FILE, so move it back.
+  if fileless && room_id.is_none() {
+    room_id = file_path.take().map(|value| value.to_string_lossy().into_owned());
+  }
+  if fileless && file_path.is_some() {
+    return Err(anyhow!("FILE cannot be combined with --watch or --text"));
+  }
+  if let Some(path) = args.qr_file.as_deref() {
+    QrImageFormat::from_path(path)?;
+  }
+  let files = match args.watch {
+    Some(dir) => FileFeed::Shared(spawn_watcher(dir).await?),
+    None => {
+      let file_info = match args.text {
+        Some(text) => snippet_file_info(text).await?,
+        None => {
+          let file_path = file_path.ok_or_else(|| anyhow!("File path is required (usage: send <FILE>)"))?;
+          load_file_info(&file_path).await?
+        }
+      };
+      if args.manual_signaling {
+        return run_send_manual(file_info, args.no_encrypt, args.sender_name, args.ice, args.timeouts).await;
+      }
+      FileFeed::Single(Arc::new(file_info))
+    }
+  };
+  let proxy = args.proxy.as_deref();
+  let watching = matches!(files, FileFeed::Shared(_));
+  let stay_open = args.stay_open || watching;
+  let timeouts = args.timeouts;
+  let ice = Arc::new(args.ice);
+  let mut endpoint_override = args.endpoint;
   let mut room_key: Option<Vec<u8>> = None;
-  let client_id = Uuid::new_v4().to_string();
-  let room_id = match room_id {
-    Some(value) => {
-      let parsed = parse_room_input(value)?;
+  let mut client_id = Uuid::new_v4().to_string();
+  let encrypt = !args.no_encrypt;
+  let room_id = match (args.room.as_deref(), room_id) {
+    (Some(name), _) => {
+      let saved = SavedRooms::load().await?.get(name)?;
+      room_key = saved.send_key(name, encrypt)?;
+      if endpoint_override.is_none() {
+        endpoint_override = saved.endpoint;
+      }
+      // The server gives the offerer role in a room to whoever created it.
+      client_id = saved.creator_cid;
+      saved.room_id
+    }
+    (None, Some(value)) => {
+      if args.max_concurrent.is_some() {
+        log_line("[room] warning", "--max-concurrent only applies to newly created rooms; ignoring");
+      }
+      let parsed = parse_room_input(&value)?;
       if endpoint_override.is_none() {
         endpoint_override = parsed.endpoint;
       }
       room_key = parsed.key;
       parsed.room_id
     }
-    None => create_room(endpoint_override.as_deref(), Some(&client_id)).await?,
+    (None, None) => create_room(endpoint_override.as_deref(), proxy, Some(&client_id), args.max_concurrent).await?,
   };
-  let encrypt = !no_encrypt;
   let room_key = if encrypt {
     Some(match room_key {
       Some(key) => key,
@@ -258,38 +980,67 @@ async fn run_send(
   };
   let ws_url = build_ws_url(endpoint_override.as_deref(), &room_id, &client_id)?;
 
-  log_line("[room] id", &room_id);
-  log_line(
-    "[room] url",
-    &build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?,
-  );
-  log_line("[ws] connecting", &ws_url.to_string());
-  let (ws_stream, _) = connect_async(ws_url.to_string())
-    .await
-    .context("connect signaling websocket")?;
-  let (mut ws_write, mut ws_read) = ws_stream.split();
-
-  let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
-  let (success_tx, mut success_rx) = mpsc::unbounded_channel::<()>();
-  let success_tx = if stay_open { None } else { Some(success_tx) };
-
-  let writer = tokio::spawn(async move {
-    while let Some(msg) = signal_rx.recv().await {
-      let text = serde_json::to_string(&msg).map_err(|err| anyhow!(err))?;
-      ws_write.send(Message::Text(text)).await.map_err(|err| anyhow!(err))?;
-    }
-    Ok::<(), anyhow::Error>(())
+  let room_url = build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
   });
+  // Stdout belongs to the JSON events then, so the QR code is only printed on request.
+  let print_qr = args.qr || (!args.no_qr && !JSON_EVENTS.load(Ordering::Relaxed) && std::io::stdout().is_terminal());
+  show_room_qr(&room_url, print_qr, args.qr_file.as_deref())?;
+  log_line("[ws] connecting", ws_url.as_ref());
+  let ws_stream = connect_signaling(&ws_url, proxy).await?;
+  let (ws_write, mut ws_read) = split_signaling(ws_stream);
+
+  let (signal_tx, signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+  let (success_tx, mut success_rx) = mpsc::unbounded_channel::<String>();
+  // A share with a deadline keeps counting receivers, even if it never stops for them.
+  let counting = !stay_open || args.expires.is_some();
+  let success_tx = if counting { Some(success_tx) } else { None };
+  // A plain `send` has one receiver, so its decline fails the run; otherwise the slot is freed.
+  let single_receiver =
+    !stay_open && args.max_receivers.is_none() && args.max_downloads.is_none() && args.expires.is_none();
+  let (declined_tx, mut declined_rx) = mpsc::unbounded_channel::<String>();
+  let (failure_tx, mut failure_rx) = mpsc::unbounded_channel::<CliError>();
+  let writer = spawn_signal_writer(ws_write, signal_rx);
+  let receiver_limit = match args.max_downloads.or(args.max_receivers) {
+    Some(limit) => Some(limit as usize),
+    None if args.expires.is_some() => None,
+    None => Some(1),
+  };
+  let mut tally = DownloadTally {
+    limit: receiver_limit,
+    quota: args.max_downloads.map(|quota| quota as usize),
+    ..DownloadTally::default()
+  };
+  let expiry = sleep(args.expires.unwrap_or_default());
+  tokio::pin!(expiry);
 
   let peers: Arc<Mutex<HashMap<String, Arc<OffererPeer>>>> = Arc::new(Mutex::new(HashMap::new()));
-  let file_info = Arc::new(file_info);
+  let mut peer_clocks: HashMap<String, Arc<Mutex<Activity>>> = HashMap::new();
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let ctx = SendContext {
+    signal_tx: signal_tx.clone(),
+    files,
+    crypto,
+    success_tx,
+    declined_tx: (!single_receiver).then_some(declined_tx),
+    // A vanished `--watch` file is logged and skipped rather than ending the run.
+    failure_tx: (!watching).then_some(failure_tx),
+    ice,
+    activity: activity.clone(),
+    sender_name: args.sender_name,
+    inbound: None,
+    release_idle: watching,
+  };
 
-  let mut completed = false;
+  let mut ticker = interval(TIMEOUT_CHECK_INTERVAL);
+  let mut outcome = Err(CliError::Signaling("connection closed".to_string()));
   loop {
     tokio::select! {
       msg = ws_read.next() => {
         let msg = match msg {
-          Some(msg) => msg.context("websocket read")?,
+          Some(msg) => msg.map_err(CliError::websocket)?,
           None => break,
         };
         if let Message::Text(text) = msg {
@@ -300,30 +1051,47 @@ async fn run_send(
 
           match parsed {
             ServerMessage::Role { role, cid } => {
-              log_line("[ws] role", &format!("{role} ({cid})"));
+              emit(Event::Role { role: &role, client_id: &cid });
               if role != "offerer" {
-                return Err(anyhow!("This command must be the offerer; connect first or use receive."));
+                return Err(CliError::Role("This command must be the offerer; connect first or use receive.").into());
               }
             }
             ServerMessage::Peers { count } => {
-              log_line("[ws] peers", &count.to_string());
+              emit(Event::Peers { count });
             }
             ServerMessage::Wait { position } => {
-              let label = position.map(|p| p.to_string()).unwrap_or_else(|| "waiting".to_string());
-              log_line("[ws] queue", &label);
+              emit(Event::Queue { position, requeued: false });
             }
-            ServerMessage::Start { peer_id } => {
-              if let Some(peer_id) = peer_id {
-                let peer = create_offerer_peer(
-                  peer_id.clone(),
-                  signal_tx.clone(),
-                  file_info.clone(),
-                  crypto.clone(),
-                  success_tx.clone(),
-                )
-                .await?;
-                peers.lock().await.insert(peer_id.clone(), peer);
+            ServerMessage::Start { peer_id: Some(peer_id) } => {
+              // Receivers whose connection failed without a `peer-left` no longer count.
+              if tally.quota.is_some() {
+                let mut guard = peers.lock().await;
+                guard.retain(|_, peer| {
+                  !matches!(
+                    peer.pc.connection_state(),
+                    RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+                  )
+                });
+                let full = tally.quota_reached(guard.keys());
+                drop(guard);
+                if full {
+                  log_line("[send] refused", &format!("{peer_id} (download limit reached)"));
+                  let _ = signal_tx.send(ClientMessage::Refuse {
+                    peer_id,
+                    reason: "download limit reached".to_string(),
+                  });
+                  continue;
+                }
               }
+              let clock = Arc::new(Mutex::new(Activity::new()));
+              clock.lock().await.enter(Phase::Connecting);
+              let peer_ctx = SendContext {
+                activity: clock.clone(),
+                ..ctx.clone()
+              };
+              let peer = create_offerer_peer(peer_id.clone(), peer_ctx).await?;
+              peers.lock().await.insert(peer_id.clone(), peer);
+              peer_clocks.insert(peer_id.clone(), clock);
             }
             ServerMessage::Answer { from, sid, sdp } => {
               if let Some(peer) = peers.lock().await.get(&from).cloned() {
@@ -343,7 +1111,57 @@ async fn run_send(
           }
         }
       }
-      _ = success_rx.recv(), if !stay_open => {
+      _ = ticker.tick() => {
+        // Each receiver has its own connect/idle clock; the wait clock runs while none is busy.
+        let live: HashSet<String> = peers.lock().await.keys().cloned().collect();
+        peer_clocks.retain(|peer_id, _| live.contains(peer_id));
+        let mut busy = false;
+        let mut stalled = Vec::new();
+        for (peer_id, clock) in &peer_clocks {
+          let clock = clock.lock().await;
+          if clock.phase == Phase::Waiting {
+            continue;
+          }
+          match clock.check(&timeouts) {
+            Ok(()) => busy = true,
+            // One stalled receiver must not end a share that others are still using.
+            Err(err) if !single_receiver => stalled.push((peer_id.clone(), err)),
+            Err(err) => return Err(err.into()),
+          }
+        }
+        for (peer_id, err) in stalled {
+          log_line("[send] dropped", &format!("{peer_id}: {err}"));
+          peer_clocks.remove(&peer_id);
+          if let Some(peer) = peers.lock().await.remove(&peer_id) {
+            let _ = peer.pc.close().await;
+          }
+          // Tells the receiver why and frees its slot in the room.
+          let _ = signal_tx.send(ClientMessage::Refuse {
+            peer_id,
+            reason: err.to_string(),
+          });
+        }
+        let mut clock = activity.lock().await;
+        if busy {
+          clock.touch();
+        } else {
+          clock.check(&timeouts)?;
+        }
+      }
+      Some(peer_id) = success_rx.recv(), if counting => {
+        let done = tally.finish(peer_id, chrono::Local::now().format("%H:%M:%S").to_string());
+        let finished = tally.finished.len();
+        match receiver_limit {
+          _ if done => {}
+          Some(limit) => {
+            log_line("[send] receivers", &format!("{finished}/{limit} finished"));
+            continue;
+          }
+          None => {
+            log_line("[send] receivers", &format!("{finished} finished"));
+            continue;
+          }
+        }
         log_line("[send] completed", "transfer done");
         let peers_snapshot = {
           let guard = peers.lock().await;
@@ -352,33 +1170,120 @@ async fn run_send(
         for peer in peers_snapshot {
           let _ = peer.pc.close().await;
         }
-        completed = true;
+        outcome = Ok(());
+        break;
+      }
+      Some(peer_id) = declined_rx.recv() => {
+        log_line("[send] receivers", &format!("{peer_id} declined, not counted"));
+        tally.declined.insert(peer_id);
+      }
+      _ = &mut expiry, if args.expires.is_some() => {
+        log_line("[send] expired", "closing the share");
+        let peers_snapshot = {
+          let guard = peers.lock().await;
+          guard.values().cloned().collect::<Vec<_>>()
+        };
+        for peer in peers_snapshot {
+          let _ = peer.pc.close().await;
+        }
+        outcome = Ok(());
+        break;
+      }
+      Some(err) = failure_rx.recv() => {
+        outcome = Err(err);
         break;
       }
     }
   }
-  if completed {
-    writer.abort();
+  writer.abort();
+  if args.expires.is_some() || tally.quota.is_some() {
+    log_line("[send] summary", &format!("{} receiver(s) downloaded", tally.downloads.len()));
+    for (peer_id, time) in &tally.downloads {
+      log_line("[send] downloaded", &format!("{peer_id} at {time}"));
+    }
+  }
+  outcome.map_err(anyhow::Error::from)
+}
+
+async fn run_room_create(args: RoomCreateArgs) -> Result<()> {
+  let name = args.name;
+  if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+    return Err(anyhow!("Invalid room name '{name}' (use letters, digits, '-', '_' and '.')"));
+  }
+  let mut saved = SavedRooms::load().await?;
+  if saved.rooms.contains_key(&name) {
+    return Err(anyhow!("A room named '{name}' already exists (see `pairlane room list`)"));
+  }
+  // Saved as resolved, so the room keeps working without the profile or env that chose it.
+  let endpoint = base_endpoint_url(args.endpoint.as_deref())?.as_str().trim_end_matches('/').to_string();
+  let creator_cid = Uuid::new_v4().to_string();
+  let room_id = create_room(Some(&endpoint), args.proxy.as_deref(), Some(&creator_cid), args.max_concurrent).await?;
+  let key = if args.no_encrypt {
+    None
+  } else {
+    Some(b64url_encode(&generate_key()?))
+  };
+  let room = SavedRoom {
+    room_id,
+    creator_cid,
+    endpoint: Some(endpoint),
+    key,
+    created_at: chrono::Local::now().format("%Y-%m-%dT%H:%M:%S%:z").to_string(),
+  };
+  let url = room.url()?;
+  emit(Event::Room {
+    room_id: &room.room_id,
+    url: Some(&url),
+  });
+  saved.rooms.insert(name.clone(), room);
+  saved.save().await?;
+  log_line("[room] saved", &format!("{name} (send with `pairlane send FILE --room {name}`)"));
+  Ok(())
+}
+
+async fn run_room_list() -> Result<()> {
+  let saved = SavedRooms::load().await?;
+  if JSON_EVENTS.load(Ordering::Relaxed) {
+    for (name, room) in &saved.rooms {
+      emit(Event::SavedRoom {
+        name,
+        room_id: &room.room_id,
+        url: &room.url()?,
+        created_at: &room.created_at,
+      });
+    }
+    return Ok(());
+  }
+  if saved.rooms.is_empty() {
+    log_line("[room] list", "no saved rooms (create one with `pairlane room create --name NAME`)");
     return Ok(());
   }
-  drop(signal_tx);
-  writer.await??;
+  let width = saved.rooms.keys().map(|name| name.len()).max().unwrap_or(0);
+  for (name, room) in &saved.rooms {
+    let created = room.created_at.get(..10).unwrap_or(&room.created_at);
+    println!("{name:<width$}  {}  {created}  {}", room.room_id, room.url()?);
+  }
   Ok(())
 }
 
-async fn run_receive(
-  room_input: &str,
-  output_dir: &Path,
-  endpoint: Option<&str>,
-  key: Option<&str>,
-  stay_open: bool,
-) -> Result<()> {
-  let parsed = parse_room_input(room_input)?;
+async fn run_receive(args: ReceiveArgs) -> Result<()> {
+  if args.manual_signaling {
+    return run_receive_manual(args).await;
+  }
+  let room_input = args
+    .room_id
+    .or(args.room_input)
+    .ok_or_else(|| anyhow!("Room ID or URL is required (usage: receive <ROOM_ID_OR_URL>)"))?;
+  let proxy = args.proxy.as_deref();
+  let stay_open = args.stay_open;
+  let timeouts = args.timeouts;
+  let ice = Arc::new(args.ice);
+  let parsed = parse_room_input(&room_input)?;
   let mut key_override = parsed.key;
-  if let Some(key) = key {
-    key_override = Some(b64url_decode(key)?);
+  if let Some(key) = args.key.as_deref() {
+    key_override = Some(decode_key(key)?);
   }
-  let endpoint_override = endpoint.or(parsed.endpoint.as_deref());
+  let endpoint_override = args.endpoint.as_deref().or(parsed.endpoint.as_deref());
   let crypto = match key_override.as_deref() {
     Some(key) => Some(Arc::new(build_crypto(key)?)),
     None => None,
@@ -387,43 +1292,47 @@ async fn run_receive(
   let client_id = Uuid::new_v4().to_string();
   let ws_url = build_ws_url(endpoint_override, &room_id, &client_id)?;
 
-  log_line("[room] id", &room_id);
-  log_line("[ws] connecting", &ws_url.to_string());
-  let (ws_stream, _) = connect_async(ws_url.to_string())
-    .await
-    .context("connect signaling websocket")?;
-  let (mut ws_write, mut ws_read) = ws_stream.split();
+  emit(Event::Room {
+    room_id: &room_id,
+    url: None,
+  });
+  log_line("[ws] connecting", ws_url.as_ref());
+  let ws_stream = connect_signaling(&ws_url, proxy).await?;
+  let (ws_write, mut ws_read) = split_signaling(ws_stream);
 
-  let (signal_tx, mut signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+  let (signal_tx, signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
   let (success_tx, mut success_rx) = mpsc::unbounded_channel::<()>();
   let success_tx = if stay_open { None } else { Some(success_tx) };
-
-  let writer = tokio::spawn(async move {
-    while let Some(msg) = signal_rx.recv().await {
-      let text = serde_json::to_string(&msg).map_err(|err| anyhow!(err))?;
-      ws_write.send(Message::Text(text)).await.map_err(|err| anyhow!(err))?;
-    }
-    Ok::<(), anyhow::Error>(())
+  let (failure_tx, mut failure_rx) = mpsc::unbounded_channel::<CliError>();
+  let writer = spawn_signal_writer(ws_write, signal_rx);
+
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let mut progress = ReceiveProgress::new(args.output_dir.clone(), crypto, success_tx, activity.clone());
+  progress.failure_tx = Some(failure_tx);
+  progress.screening.confirm = !args.yes && prompt_available();
+  progress.screening.policy = args.policy;
+  progress.saving.output_file = args.output_file;
+  progress.saving.name_template = args.name_template;
+  let hooks = args.exec.map(|command| {
+    let (exec_tx, handle) = spawn_exec_hooks(command, None);
+    progress.reports.exec_tx = Some(exec_tx);
+    handle
   });
+  let session = ReceiverSession {
+    signal_tx,
+    receiver_state: Arc::new(Mutex::new(None)),
+    progress: Arc::new(Mutex::new(progress)),
+    activity: activity.clone(),
+    ice,
+  };
 
-  let receiver_state: Arc<Mutex<Option<ReceiverState>>> = Arc::new(Mutex::new(None));
-  let progress = Arc::new(Mutex::new(ReceiveProgress {
-    output_dir: output_dir.to_path_buf(),
-    current_file: None,
-    file: None,
-    expected_size: 0,
-    received: 0,
-    encrypted: false,
-    crypto,
-    success_tx,
-  }));
-
-  let mut completed = false;
+  let mut ticker = interval(TIMEOUT_CHECK_INTERVAL);
+  let mut outcome = Err(CliError::Signaling("connection closed".to_string()));
   loop {
     tokio::select! {
       msg = ws_read.next() => {
         let msg = match msg {
-          Some(msg) => msg.context("websocket read")?,
+          Some(msg) => msg.map_err(CliError::websocket)?,
           None => break,
         };
         if let Message::Text(text) = msg {
@@ -431,112 +1340,905 @@ async fn run_receive(
             Ok(msg) => msg,
             Err(_) => continue,
           };
-
-          match parsed {
-            ServerMessage::Role { role, cid } => {
-              log_line("[ws] role", &format!("{role} ({cid})"));
-              if role != "answerer" {
-                return Err(anyhow!("This command must be the answerer; connect after the sender."));
-              }
-            }
-            ServerMessage::Peers { count } => {
-              log_line("[ws] peers", &count.to_string());
-            }
-            ServerMessage::Wait { position } => {
-              let label = position.map(|p| p.to_string()).unwrap_or_else(|| "waiting".to_string());
-              log_line("[ws] queue", &label);
-            }
-            ServerMessage::Start { .. } => {
-              let pc = create_peer_connection().await?;
-              let tx = signal_tx.clone();
-              let receiver_state_for_ice = receiver_state.clone();
-              pc.on_ice_candidate(Box::new(move |candidate| {
-                let tx = tx.clone();
-                let receiver_state = receiver_state_for_ice.clone();
-                Box::pin(async move {
-                  if let Some(candidate) = candidate {
-                    let candidate = candidate.to_json().unwrap_or_default();
-                    let guard = receiver_state.lock().await;
-                    if let Some(state) = guard.as_ref() {
-                      if let (Some(peer_id), Some(sid)) = (state.peer_id.clone(), state.active_sid) {
-                        let _ = tx.send(ClientMessage::Candidate { to: peer_id, sid, candidate });
-                      }
-                    }
-                  }
-                })
-              }));
-
-              let rx_progress = progress.clone();
-              pc.on_data_channel(Box::new(move |dc| {
-                let rx_progress = rx_progress.clone();
-                Box::pin(async move {
-                  wire_receiver_channel(dc, rx_progress).await;
-                })
-              }));
-
-              *receiver_state.lock().await = Some(ReceiverState {
-                pc,
-                peer_id: None,
-                active_sid: None,
-                pending_candidates: Vec::new(),
-                remote_desc_set: false,
-              });
-            }
-            ServerMessage::Offer { from, sid, sdp } => {
-              let mut guard = receiver_state.lock().await;
-              let state = guard.as_mut().ok_or_else(|| anyhow!("Receiver not initialized"))?;
-              state.peer_id = Some(from.clone());
-              state.active_sid = Some(sid);
-              state.pc.set_remote_description(sdp).await?;
-              state.remote_desc_set = true;
-              flush_receiver_candidates(state).await?;
-
-              let answer = state.pc.create_answer(None).await?;
-              state.pc.set_local_description(answer).await?;
-              if let Some(local) = state.pc.local_description().await {
-                let _ = signal_tx.send(ClientMessage::Answer { to: from, sid, sdp: local });
-              }
-            }
-            ServerMessage::Candidate { from: _, sid, candidate } => {
-              let mut guard = receiver_state.lock().await;
-              if let Some(state) = guard.as_mut() {
-                handle_receiver_candidate(state, sid, candidate).await?;
-              }
-            }
-            _ => {}
-          }
+          handle_receiver_message(&session, parsed, args.fail_on_sender_loss).await?;
+        }
+      }
+      _ = ticker.tick() => {
+        let guard = activity.lock().await;
+        guard.check(&timeouts)?;
+        if args.fail_on_sender_loss && guard.receiver == Some(ReceiverStatus::SenderGone) {
+          return Err(CliError::PeerLost("Lost the peer connection to the sender".to_string()).into());
         }
       }
       _ = success_rx.recv(), if !stay_open => {
         log_line("[recv] completed", "transfer done");
-        if let Some(state) = receiver_state.lock().await.take() {
+        if let Some(state) = session.receiver_state.lock().await.take() {
           let _ = state.pc.close().await;
         }
-        completed = true;
+        outcome = Ok(());
+        break;
+      }
+      Some(err) = failure_rx.recv() => {
+        reset_receiver(&session.receiver_state, &session.progress).await;
+        outcome = Err(err);
         break;
       }
     }
   }
-  if completed {
-    writer.abort();
-    return Ok(());
+  // Let queued hooks finish, even after a failure; dropping the last sender ends the runner.
+  session.progress.lock().await.reports.exec_tx = None;
+  if let Some(hooks) = hooks {
+    let _ = hooks.await;
   }
-  drop(signal_tx);
-  writer.await??;
-  Ok(())
+  writer.abort();
+  outcome.map_err(anyhow::Error::from)
 }
 
-async fn create_offerer_peer(
+async fn handle_receiver_message(session: &ReceiverSession, msg: ServerMessage, fail_on_sender_loss: bool) -> Result<()> {
+  match msg {
+    ServerMessage::Role { role, cid } => {
+      emit(Event::Role { role: &role, client_id: &cid });
+      if role != "answerer" {
+        return Err(CliError::Role("This command must be the answerer; connect after the sender.").into());
+      }
+    }
+    ServerMessage::Peers { count } => {
+      emit(Event::Peers { count });
+    }
+    ServerMessage::Wait { position } => {
+      // After `start`, a fresh `wait` means the server requeued us because the sender left.
+      let engaged = session.activity.lock().await.receiver.is_some_and(ReceiverStatus::is_engaged);
+      if !engaged {
+        session.activity.lock().await.enter_receiver(ReceiverStatus::Queued(position));
+        return Ok(());
+      }
+      session.activity.lock().await.enter_receiver(ReceiverStatus::SenderGone);
+      if fail_on_sender_loss {
+        return Err(CliError::PeerLost("Sender left the room before the transfer finished".to_string()).into());
+      }
+      reset_receiver(&session.receiver_state, &session.progress).await;
+      session.activity.lock().await.enter_receiver(ReceiverStatus::Requeued(position));
+    }
+    ServerMessage::PeerLeft { peer_id } => {
+      let current = session.receiver_state.lock().await.as_ref().and_then(|state| state.peer_id.clone());
+      if current.as_deref() != Some(peer_id.as_str()) {
+        return Ok(());
+      }
+      // A sender leaving after the transfer completed is no loss, even with --fail-on-sender-loss.
+      let active = session.activity.lock().await.receiver.is_some_and(ReceiverStatus::is_active);
+      if active {
+        session.activity.lock().await.enter_receiver(ReceiverStatus::SenderGone);
+        if fail_on_sender_loss {
+          return Err(CliError::PeerLost("Sender left the room before the transfer finished".to_string()).into());
+        }
+      }
+      reset_receiver(&session.receiver_state, &session.progress).await;
+    }
+    ServerMessage::Refused { reason } => {
+      reset_receiver(&session.receiver_state, &session.progress).await;
+      let reason = reason.unwrap_or_else(|| "no reason given".to_string());
+      return Err(CliError::Refused(format!("the sender turned this receiver away: {reason}")).into());
+    }
+    ServerMessage::Start { .. } => {
+      session.activity.lock().await.enter_receiver(ReceiverStatus::Connecting);
+      init_receiver(
+        session.signal_tx.clone(),
+        session.receiver_state.clone(),
+        session.progress.clone(),
+        session.ice.clone(),
+        None,
+      )
+      .await?;
+    }
+    ServerMessage::Offer { from, sid, sdp } => {
+      let mut guard = session.receiver_state.lock().await;
+      let state = guard.as_mut().ok_or_else(|| anyhow!("Receiver not initialized"))?;
+      session.progress.lock().await.sender = Some(from.clone());
+      accept_offer(state, from, sid, sdp, &session.signal_tx).await?;
+    }
+    ServerMessage::Candidate { from: _, sid, candidate } => {
+      let mut guard = session.receiver_state.lock().await;
+      if let Some(state) = guard.as_mut() {
+        handle_receiver_candidate(state, sid, candidate).await?;
+      }
+    }
+    _ => {}
+  }
+  Ok(())
+}
+
+async fn init_receiver(
+  signal_tx: mpsc::UnboundedSender<ClientMessage>,
+  receiver_state: Arc<Mutex<Option<ReceiverState>>>,
+  progress: Arc<Mutex<ReceiveProgress>>,
+  ice: Arc<IceArgs>,
+  outbound: Option<SendContext>,
+) -> Result<()> {
+  let activity = {
+    let mut guard = progress.lock().await;
+    guard.peer_caps = Capabilities::default();
+    guard.sender = None;
+    guard.sender_name = None;
+    guard.activity.clone()
+  };
+  let pc = open_answerer_peer(signal_tx, receiver_state, activity, ice).await?;
+
+  let rx_progress = progress.clone();
+  let weak_pc = Arc::downgrade(&pc);
+  pc.on_data_channel(Box::new(move |dc| {
+    let rx_progress = rx_progress.clone();
+    let outbound = outbound.clone();
+    let pc = weak_pc.clone();
+    Box::pin(async move {
+      rx_progress.lock().await.activity.lock().await.enter_receiver(ReceiverStatus::Transferring);
+      let Some(ctx) = outbound else {
+        wire_receiver_channel(dc, rx_progress, None).await;
+        return;
+      };
+      let (control_tx, mut control_rx) = mpsc::unbounded_channel::<DataMessage>();
+      wire_receiver_channel(dc.clone(), rx_progress, Some(control_tx)).await;
+      let open_dc = dc.clone();
+      dc.on_open(Box::new(move || {
+        Box::pin(async move {
+          let max_message_size = remote_max_message_size(&pc).await;
+          if let Err(err) = send_queued_files(&open_dc, &mut control_rx, &ctx, max_message_size, None).await {
+            log_error("send", &format!("{err:#}"));
+          }
+        })
+      }));
+    })
+  }));
+  Ok(())
+}
+
+async fn open_answerer_peer(
+  signal_tx: mpsc::UnboundedSender<ClientMessage>,
+  receiver_state: Arc<Mutex<Option<ReceiverState>>>,
+  activity: Arc<Mutex<Activity>>,
+  ice: Arc<IceArgs>,
+) -> Result<Arc<RTCPeerConnection>> {
+  let pc = create_peer_connection(&ice).await?;
+  let receiver_state_for_ice = receiver_state.clone();
+  let ice_for_candidates = ice.clone();
+  pc.on_ice_candidate(Box::new(move |candidate| {
+    let tx = signal_tx.clone();
+    let receiver_state = receiver_state_for_ice.clone();
+    let ice = ice_for_candidates.clone();
+    Box::pin(async move {
+      if let Some(candidate) = candidate.and_then(|candidate| ice.expose(&candidate)) {
+        let guard = receiver_state.lock().await;
+        if let Some(state) = guard.as_ref() {
+          if let (Some(peer_id), Some(sid)) = (state.peer_id.clone(), state.active_sid) {
+            let _ = tx.send(ClientMessage::Candidate { to: peer_id, sid, candidate });
+          }
+        }
+      }
+    })
+  }));
+
+  let state_clone = receiver_state.clone();
+  pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+    let activity = activity.clone();
+    let receiver_state = state_clone.clone();
+    Box::pin(async move {
+      if state == RTCPeerConnectionState::Connected {
+        let peer_id = receiver_state.lock().await.as_ref().and_then(|state| state.peer_id.clone());
+        emit(Event::PeerConnected { peer_id });
+      } else {
+        log_line("[rtc] connectionState", &format!("{:?}", state));
+      }
+      if state == RTCPeerConnectionState::Failed {
+        let mut activity = activity.lock().await;
+        if activity.receiver.is_some_and(ReceiverStatus::is_active) {
+          activity.enter_receiver(ReceiverStatus::SenderGone);
+        }
+      }
+    })
+  }));
+
+  *receiver_state.lock().await = Some(ReceiverState {
+    pc: pc.clone(),
+    ice,
+    peer_id: None,
+    active_sid: None,
+    pending_candidates: Vec::new(),
+    remote_desc_set: false,
+  });
+  Ok(pc)
+}
+
+async fn reset_receiver(receiver_state: &Mutex<Option<ReceiverState>>, progress: &Mutex<ReceiveProgress>) {
+  if let Some(state) = receiver_state.lock().await.take() {
+    let _ = state.pc.close().await;
+  }
+  let mut guard = progress.lock().await;
+  guard.file = None;
+  guard.snippet = None;
+  guard.encrypted = false;
+  guard.hasher = None;
+  if let Some(path) = guard.current_file.take() {
+    let path = set_aside(path, TransferStatus::Incomplete);
+    log_line("[recv] incomplete", &path.display().to_string());
+    guard.record(path, TransferStatus::Incomplete);
+  }
+}
+
+/// Moves an unfinished file out of the way so it is never taken for a good one: a corrupt file
+/// gets `.corrupt` appended and one cut short `.partial`, while a refused one is deleted.
+fn set_aside(path: PathBuf, status: TransferStatus) -> PathBuf {
+  let suffix = match status {
+    TransferStatus::ChecksumMismatch => ".corrupt",
+    TransferStatus::Refused => {
+      let _ = std::fs::remove_file(&path);
+      return path;
+    }
+    _ => ".partial",
+  };
+  let mut renamed = path.clone().into_os_string();
+  renamed.push(suffix);
+  let renamed = PathBuf::from(renamed);
+  match std::fs::rename(&path, &renamed) {
+    Ok(()) => renamed,
+    Err(_) => path,
+  }
+}
+
+async fn accept_offer(
+  state: &mut ReceiverState,
+  from: String,
+  sid: u64,
+  sdp: RTCSessionDescription,
+  signal_tx: &mpsc::UnboundedSender<ClientMessage>,
+) -> Result<()> {
+  state.peer_id = Some(from.clone());
+  state.active_sid = Some(sid);
+  state.pc.set_remote_description(sdp).await?;
+  state.remote_desc_set = true;
+  flush_receiver_candidates(state).await?;
+
+  let answer = state.pc.create_answer(None).await?;
+  state.pc.set_local_description(answer).await?;
+  if let Some(mut local) = state.pc.local_description().await {
+    local.sdp = state.ice.filter_sdp(&local.sdp);
+    let _ = signal_tx.send(ClientMessage::Answer { to: from, sid, sdp: local });
+  }
+  Ok(())
+}
+
+impl Activity {
+  fn new() -> Self {
+    Self {
+      phase: Phase::Waiting,
+      since: Instant::now(),
+      receiver: None,
+    }
+  }
+
+  fn enter_receiver(&mut self, status: ReceiverStatus) {
+    if self.receiver != Some(status) {
+      match status {
+        ReceiverStatus::Queued(position) => emit(Event::Queue {
+          position,
+          requeued: false,
+        }),
+        ReceiverStatus::Requeued(position) => emit(Event::Queue {
+          position,
+          requeued: true,
+        }),
+        _ => log_line("[recv] state", &status.describe()),
+      }
+    }
+    self.receiver = Some(status);
+    self.enter(status.phase());
+  }
+
+  fn enter(&mut self, phase: Phase) {
+    self.phase = phase;
+    self.since = Instant::now();
+  }
+
+  fn touch(&mut self) {
+    self.since = Instant::now();
+  }
+
+  fn check(&self, timeouts: &TimeoutArgs) -> Result<(), CliError> {
+    let limit = match self.phase {
+      Phase::Waiting => timeouts.wait_timeout,
+      Phase::Connecting => timeouts.connect_timeout,
+      Phase::Transferring => timeouts.idle_timeout,
+    };
+    let Some(limit) = limit.filter(|limit| self.since.elapsed() >= *limit) else {
+      return Ok(());
+    };
+    Err(match self.phase {
+      Phase::Waiting => CliError::WaitTimeout(limit),
+      Phase::Connecting => CliError::ConnectTimeout(limit),
+      Phase::Transferring => CliError::IdleTimeout(limit),
+    })
+  }
+}
+
+impl ReceiveProgress {
+  fn new(
+    output_dir: PathBuf,
+    crypto: Option<Arc<Aes256Gcm>>,
+    success_tx: Option<mpsc::UnboundedSender<()>>,
+    activity: Arc<Mutex<Activity>>,
+  ) -> Self {
+    Self {
+      current_file: None,
+      file: None,
+      expected_size: 0,
+      received: 0,
+      encrypted: false,
+      crypto,
+      success_tx,
+      failure_tx: None,
+      activity,
+      peer_caps: Capabilities::default(),
+      hasher: None,
+      sender: None,
+      sender_name: None,
+      snippet: None,
+      file_name: String::new(),
+      mime: String::new(),
+      digest: None,
+      last_progress: Instant::now(),
+      saving: SaveRules {
+        output_dir,
+        inbox_sort: None,
+        output_file: None,
+        name_template: None,
+        print_snippets: true,
+      },
+      screening: Screening::default(),
+      reports: TransferReports::default(),
+    }
+  }
+
+  fn sender_label(&self) -> String {
+    self
+      .sender_name
+      .clone()
+      .or_else(|| self.sender.clone())
+      .unwrap_or_else(|| "unknown".to_string())
+  }
+
+  /// The user turned down an offer at the prompt, which ends `receive` with its own exit code.
+  async fn decline(&mut self, name: &str, reason: &str) {
+    self.activity.lock().await.enter_receiver(ReceiverStatus::Done);
+    self.fail(CliError::Declined(format!("{name}: {reason}")));
+  }
+
+  /// Turns down an offer that breaks the receive policy. Like any failure this ends `receive`,
+  /// while `inbox` logs it and waits for the next file.
+  fn refuse(&mut self, name: &str, reason: String) {
+    self.record(self.saving.output_dir.join(name), TransferStatus::Refused);
+    self.fail(CliError::Refused(format!("{name}: {reason}")));
+  }
+
+  /// Abandons the current file, setting aside what was written, and reports why.
+  fn fail(&mut self, err: CliError) {
+    self.file = None;
+    self.snippet = None;
+    self.encrypted = false;
+    self.hasher = None;
+    if let Some(path) = self.current_file.take() {
+      let status = match err {
+        CliError::Checksum(_) => TransferStatus::ChecksumMismatch,
+        CliError::Refused(_) => TransferStatus::Refused,
+        _ => TransferStatus::Incomplete,
+      };
+      self.record(set_aside(path, status), status);
+    }
+    match self.failure_tx.as_ref() {
+      Some(tx) => {
+        let _ = tx.send(err);
+      }
+      None => log_error("recv", &err.to_string()),
+    }
+  }
+
+  fn record(&self, path: PathBuf, status: TransferStatus) {
+    if let Some(tx) = self.reports.journal_tx.as_ref() {
+      let _ = tx.send(TransferRecord {
+        status,
+        sender: self.sender_label(),
+        path,
+        bytes: self.received,
+      });
+    }
+  }
+}
+
+impl SaveRules {
+  /// Where the next file lands, for the free disk space check.
+  fn save_dir(&self) -> PathBuf {
+    match self.output_file.as_ref().and_then(|path| path.parent()) {
+      Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+      Some(_) => PathBuf::from("."),
+      None => self.output_dir.clone(),
+    }
+  }
+
+  async fn target_path(&self, file_name: &str, sender: &str) -> Result<PathBuf> {
+    if let Some(path) = self.output_file.as_ref() {
+      return Ok(path.clone());
+    }
+    let file_name = match self.name_template.as_deref() {
+      Some(template) => sanitize_file_name(&expand_name_template(template, file_name, sender).map_err(|err| anyhow!(err))?),
+      None => file_name.to_string(),
+    };
+    let file_name = file_name.as_str();
+    let Some(sort) = self.inbox_sort else {
+      return Ok(self.output_dir.join(file_name));
+    };
+    let dir = match sort {
+      InboxSort::Flat => self.output_dir.clone(),
+      InboxSort::Date => self.output_dir.join(chrono::Local::now().format("%Y-%m-%d").to_string()),
+      InboxSort::Sender => self.output_dir.join(sanitize_file_name(sender)),
+    };
+    tokio::fs::create_dir_all(&dir)
+      .await
+      .with_context(|| format!("create {}", dir.display()))?;
+    let stem = Path::new(file_name).file_stem().and_then(|s| s.to_str()).unwrap_or(file_name);
+    let ext = Path::new(file_name)
+      .extension()
+      .and_then(|s| s.to_str())
+      .map(|ext| format!(".{ext}"))
+      .unwrap_or_default();
+    let mut path = dir.join(file_name);
+    let mut counter = 1;
+    while tokio::fs::try_exists(&path).await.unwrap_or(false) {
+      path = dir.join(format!("{stem} ({counter}){ext}"));
+      counter += 1;
+    }
+    Ok(path)
+  }
+}
+
+impl TransferStatus {
+  fn label(self) -> String {
+    match self {
+      TransferStatus::Completed => "completed".to_string(),
+      TransferStatus::Incomplete => "incomplete".to_string(),
+      TransferStatus::ChecksumMismatch => "sha256-mismatch".to_string(),
+      TransferStatus::Refused => "refused".to_string(),
+      TransferStatus::Exec(Some(code)) => format!("exec-exit-{code}"),
+      TransferStatus::Exec(None) => "exec-failed".to_string(),
+    }
+  }
+}
+
+impl SavedRooms {
+  fn path() -> Result<PathBuf> {
+    config_path()
+      .map(|path| path.with_file_name("rooms.json"))
+      .ok_or_else(|| anyhow!("Cannot locate the config directory; set HOME or XDG_CONFIG_HOME"))
+  }
+
+  async fn load() -> Result<Self> {
+    let path = Self::path()?;
+    match tokio::fs::read(&path).await {
+      Ok(data) => serde_json::from_slice(&data).with_context(|| format!("parse {}", path.display())),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+      Err(err) => Err(err).with_context(|| format!("read {}", path.display())),
+    }
+  }
+
+  async fn save(&self) -> Result<()> {
+    let path = Self::path()?;
+    if let Some(dir) = path.parent() {
+      tokio::fs::create_dir_all(dir)
+        .await
+        .with_context(|| format!("create {}", dir.display()))?;
+    }
+    write_private_json(&path, self).await
+  }
+
+  fn get(mut self, name: &str) -> Result<SavedRoom> {
+    self.rooms.remove(name).ok_or_else(|| {
+      anyhow!("No saved room named '{name}' (create it with `pairlane room create --name {name}`)")
+    })
+  }
+}
+
+impl SavedRoom {
+  /// The key to send through this room with. A fresh key would not match the URL receivers
+  /// already have, so sending an unencrypted room's files in plaintext must be asked for with
+  /// `--no-encrypt` rather than fallen into.
+  fn send_key(&self, name: &str, encrypt: bool) -> Result<Option<Vec<u8>>> {
+    match (self.key.as_deref(), encrypt) {
+      (_, false) => Ok(None),
+      (Some(key), true) => Ok(Some(decode_key(key)?)),
+      (None, true) => Err(
+        CliError::Key(format!("saved room '{name}' is unencrypted; pass --no-encrypt to send through it anyway")).into(),
+      ),
+    }
+  }
+
+  fn url(&self) -> Result<String> {
+    let key = self.key.as_deref().map(decode_key).transpose()?;
+    build_room_url_with_key(self.endpoint.as_deref(), &self.room_id, key.as_deref())
+  }
+}
+
+async fn write_private_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+  tokio::fs::write(path, serde_json::to_vec_pretty(value)?)
+    .await
+    .with_context(|| format!("write {}", path.display()))?;
+  // These files hold room keys, so keep them private to the user.
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+  }
+  Ok(())
+}
+
+impl ReceiverStatus {
+  fn phase(self) -> Phase {
+    match self {
+      ReceiverStatus::Connecting => Phase::Connecting,
+      ReceiverStatus::Transferring => Phase::Transferring,
+      ReceiverStatus::Queued(_) | ReceiverStatus::SenderGone | ReceiverStatus::Requeued(_) | ReceiverStatus::Done => {
+        Phase::Waiting
+      }
+    }
+  }
+
+  fn is_engaged(self) -> bool {
+    matches!(self, ReceiverStatus::Connecting | ReceiverStatus::Transferring | ReceiverStatus::SenderGone)
+  }
+
+  /// Paired with a sender and not finished yet; losing the sender now loses data.
+  fn is_active(self) -> bool {
+    matches!(self, ReceiverStatus::Connecting | ReceiverStatus::Transferring)
+  }
+
+  fn describe(self) -> String {
+    let position = |position: Option<u32>| position.map(|p| format!(" (position {p})")).unwrap_or_default();
+    match self {
+      ReceiverStatus::Queued(p) => format!("waiting in queue{}", position(p)),
+      ReceiverStatus::Connecting => "connecting to sender".to_string(),
+      ReceiverStatus::Transferring => "transferring".to_string(),
+      ReceiverStatus::SenderGone => "sender disconnected".to_string(),
+      ReceiverStatus::Requeued(p) => format!("requeued; waiting for the sender to return{}", position(p)),
+      ReceiverStatus::Done => "transfer complete".to_string(),
+    }
+  }
+}
+
+impl std::fmt::Display for CliError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      CliError::WaitTimeout(limit) => write!(f, "No peer joined within {}s (--wait-timeout)", limit.as_secs()),
+      CliError::ConnectTimeout(limit) => {
+        write!(f, "Peer connection did not open within {}s (--connect-timeout)", limit.as_secs())
+      }
+      CliError::IdleTimeout(limit) => write!(f, "Transfer made no progress for {}s (--idle-timeout)", limit.as_secs()),
+      CliError::Role(message) => f.write_str(message),
+      CliError::Key(detail) => write!(f, "Encryption key: {detail}"),
+      CliError::Signaling(detail) => write!(f, "Signaling: {detail}"),
+      CliError::RateLimited => f.write_str("The signaling server is rate limiting this client; try again later"),
+      CliError::PeerLost(detail) | CliError::Checksum(detail) | CliError::Storage(detail) => f.write_str(detail),
+      CliError::Refused(detail) => write!(f, "Refused: {detail}"),
+      CliError::Declined(detail) => write!(f, "Declined: {detail}"),
+    }
+  }
+}
+
+impl std::error::Error for CliError {}
+
+impl CliError {
+  fn exit_code(&self) -> i32 {
+    match self {
+      CliError::WaitTimeout(_) => 3,
+      CliError::ConnectTimeout(_) => 4,
+      CliError::IdleTimeout(_) => 5,
+      CliError::Role(_) => 6,
+      CliError::Key(_) => 7,
+      CliError::Signaling(_) => 8,
+      CliError::RateLimited => 9,
+      CliError::PeerLost(_) => 10,
+      CliError::Checksum(_) => 11,
+      CliError::Storage(_) => 12,
+      CliError::Refused(_) => 13,
+      CliError::Declined(_) => 14,
+    }
+  }
+
+  fn websocket(err: WsError) -> Self {
+    match err {
+      WsError::Http(response) if response.status().as_u16() == 429 => CliError::RateLimited,
+      err => CliError::Signaling(err.to_string()),
+    }
+  }
+}
+
+fn exit_code(err: &anyhow::Error) -> i32 {
+  match err.downcast_ref::<CliError>() {
+    Some(err) => err.exit_code(),
+    None => 1,
+  }
+}
+
+fn parse_duration(value: &str) -> Result<Duration, String> {
+  let value = value.trim();
+  let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+  let (amount, unit) = value.split_at(split);
+  let amount: u64 = amount
+    .parse()
+    .map_err(|_| format!("invalid duration '{value}' (expected e.g. 30s, 10m, 2h)"))?;
+  let scale = match unit {
+    "" | "s" => 1,
+    "m" => 60,
+    "h" => 60 * 60,
+    "d" => 60 * 60 * 24,
+    _ => return Err(format!("invalid duration unit '{unit}' (expected s, m, h or d)")),
+  };
+  amount
+    .checked_mul(scale)
+    .map(Duration::from_secs)
+    .ok_or_else(|| format!("duration '{value}' is too long"))
+}
+
+fn parse_size(value: &str) -> Result<u64, String> {
+  let value = value.trim();
+  let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+  let (amount, unit) = value.split_at(split);
+  let amount: u64 = amount
+    .parse()
+    .map_err(|_| format!("invalid size '{value}' (expected e.g. 500M, 2G)"))?;
+  // K, KB and KiB all mean 1024 bytes.
+  let unit = unit.to_ascii_lowercase();
+  let shift = match unit.trim_end_matches("ib").trim_end_matches('b') {
+    "" => 0,
+    "k" => 10,
+    "m" => 20,
+    "g" => 30,
+    "t" => 40,
+    _ => return Err(format!("invalid size unit '{unit}' (expected K, M, G or T)")),
+  };
+  amount
+    .checked_mul(1 << shift)
+    .ok_or_else(|| format!("size '{value}' is too large"))
+}
+
+fn format_size(bytes: u64) -> String {
+  const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+  if bytes < 1024 {
+    return format!("{bytes} bytes");
+  }
+  let mut value = bytes as f64 / 1024.0;
+  let mut unit = 0;
+  while value >= 1024.0 && unit + 1 < UNITS.len() {
+    value /= 1024.0;
+    unit += 1;
+  }
+  format!("{value:.1} {}", UNITS[unit])
+}
+
+/// Case-insensitive match where `*` stands for any run of characters and `?` for one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+  let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+  let text: Vec<char> = text.to_lowercase().chars().collect();
+  let (mut p, mut t) = (0, 0);
+  let mut backtrack = None;
+  while t < text.len() {
+    match pattern.get(p) {
+      Some('*') => {
+        backtrack = Some((p, t));
+        p += 1;
+      }
+      Some(&c) if c == '?' || c == text[t] => {
+        p += 1;
+        t += 1;
+      }
+      _ => match backtrack {
+        Some((star, start)) => {
+          p = star + 1;
+          t = start + 1;
+          backtrack = Some((star, start + 1));
+        }
+        None => return false,
+      },
+    }
+  }
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+impl PolicyArgs {
+  /// Why a file should be refused, if it should. The MIME type the sender declares is checked
+  /// together with the one its extension implies, so `setup.exe` sent as `image/png` is still
+  /// caught by `--deny-mime 'application/*'`. `dir` is `None` for snippets that are only printed.
+  fn refusal(&self, name: &str, size: u64, mime: &str, dir: Option<&Path>) -> Option<String> {
+    if let Some(limit) = self.max_size.filter(|&limit| size > limit) {
+      return Some(format!("{} is over the {} limit", format_size(size), format_size(limit)));
+    }
+    if !self.allow_ext.is_empty() {
+      let ext = Path::new(name).extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+      let allowed = self
+        .allow_ext
+        .iter()
+        .any(|allowed| !ext.is_empty() && allowed.trim_start_matches('.').eq_ignore_ascii_case(ext));
+      if !allowed {
+        return Some(format!("extension of {name} is not allowed"));
+      }
+    }
+    let declared = if mime.is_empty() { "application/octet-stream" } else { mime };
+    let implied = mime_guess::from_path(name).first_raw();
+    for mime in std::iter::once(declared).chain(implied) {
+      if self.deny_mime.iter().any(|pattern| glob_match(pattern, mime)) {
+        return Some(format!("type {mime} is not allowed"));
+      }
+      if !self.allow_mime.is_empty() && !self.allow_mime.iter().any(|pattern| glob_match(pattern, mime)) {
+        return Some(format!("type {mime} is not allowed"));
+      }
+    }
+    // Best effort: if the space cannot be determined, the write itself will fail.
+    if let Some(available) = dir.and_then(|dir| fs4::available_space(dir).ok()) {
+      if size > available {
+        return Some(format!("{} does not fit in the {} of free disk space", format_size(size), format_size(available)));
+      }
+    }
+    None
+  }
+}
+
+async fn wait_for_success<T>(
+  mut success_rx: mpsc::UnboundedReceiver<T>,
+  mut failure_rx: mpsc::UnboundedReceiver<CliError>,
+  activity: &Mutex<Activity>,
+  timeouts: &TimeoutArgs,
+) -> Result<()> {
+  let mut ticker = interval(TIMEOUT_CHECK_INTERVAL);
+  loop {
+    tokio::select! {
+      _ = success_rx.recv() => return Ok(()),
+      Some(err) = failure_rx.recv() => return Err(err.into()),
+      _ = ticker.tick() => activity.lock().await.check(timeouts)?,
+    }
+  }
+}
+
+/// Splits the signaling socket. The server answers every keepalive ping, so once nothing at all
+/// has arrived for `KEEPALIVE_TIMEOUT` the connection is dead and reading fails.
+fn split_signaling(
+  ws_stream: SignalingStream,
+) -> (
+  SplitSink<SignalingStream, Message>,
+  impl Stream<Item = Result<Message, WsError>> + Unpin,
+) {
+  let (ws_write, ws_read) = ws_stream.split();
+  let ws_read = futures_util::stream::unfold(ws_read, |mut ws_read| async move {
+    match tokio::time::timeout(KEEPALIVE_TIMEOUT, ws_read.next()).await {
+      Ok(msg) => msg.map(|msg| (msg, ws_read)),
+      Err(_) => {
+        let err = std::io::Error::new(std::io::ErrorKind::TimedOut, "no answer to keepalive pings");
+        Some((Err(WsError::Io(err)), ws_read))
+      }
+    }
+  });
+  (ws_write, Box::pin(ws_read))
+}
+
+fn spawn_signal_writer(
+  mut ws_write: SplitSink<SignalingStream, Message>,
+  mut signal_rx: mpsc::UnboundedReceiver<ClientMessage>,
+) -> JoinHandle<Result<()>> {
+  tokio::spawn(async move {
+    // Periodic pings keep idle proxies and load balancers from silently dropping the socket.
+    let mut keepalive = interval(KEEPALIVE_INTERVAL);
+    keepalive.tick().await;
+    loop {
+      tokio::select! {
+        msg = signal_rx.recv() => {
+          let Some(msg) = msg else { break };
+          let text = serde_json::to_string(&msg).map_err(|err| anyhow!(err))?;
+          ws_write.send(Message::Text(text)).await.map_err(|err| anyhow!(err))?;
+        }
+        _ = keepalive.tick() => {
+          ws_write.send(Message::Ping(Vec::new())).await.map_err(|err| anyhow!(err))?;
+        }
+      }
+    }
+    Ok::<(), anyhow::Error>(())
+  })
+}
+
+async fn create_offerer_peer(peer_id: String, ctx: SendContext) -> Result<Arc<OffererPeer>> {
+  let (peer, dc) = open_offerer_peer(peer_id.clone(), "file", ctx.signal_tx.clone(), ctx.ice.clone()).await?;
+  let (control_tx, control_rx) = mpsc::unbounded_channel::<DataMessage>();
+  peer.state.lock().await.control_rx = Some(control_rx);
+
+  if let Some(inbound) = ctx.inbound.clone() {
+    wire_receiver_channel(dc.clone(), inbound, Some(control_tx)).await;
+  } else {
+    dc.on_message(Box::new(move |msg: DataChannelMessage| {
+      let control_tx = control_tx.clone();
+      Box::pin(async move {
+        if !msg.is_string {
+          return;
+        }
+        if let Ok(parsed) = serde_json::from_slice::<DataMessage>(&msg.data) {
+          let _ = control_tx.send(parsed);
+        }
+      })
+    }));
+  }
+
+  let send_peer_id = peer_id.clone();
+  let send_state = peer.state.clone();
+  let dc_for_open = dc.clone();
+  let open_ctx = ctx.clone();
+  let open_pc = Arc::downgrade(&peer.pc);
+  dc.on_open(Box::new(move || {
+    let send_peer_id = send_peer_id.clone();
+    let dc = dc_for_open.clone();
+    let send_state = send_state.clone();
+    let ctx = open_ctx.clone();
+    let pc = open_pc.clone();
+    Box::pin(async move {
+      let mut guard = send_state.lock().await;
+      if guard.sending {
+        return;
+      }
+      guard.sending = true;
+      let Some(mut control_rx) = guard.control_rx.take() else {
+        return;
+      };
+      drop(guard);
+
+      let max_message_size = remote_max_message_size(&pc).await;
+      let outcome = match send_queued_files(&dc, &mut control_rx, &ctx, max_message_size, Some(&send_peer_id)).await {
+        Ok(outcome) => outcome,
+        Err(err) => {
+          // Typed errors, such as an unreadable source file, end the run; one receiver dropping
+          // out does not.
+          match (ctx.failure_tx.as_ref(), err.downcast::<CliError>()) {
+            (Some(tx), Ok(err)) => {
+              let _ = tx.send(err);
+            }
+            (None, Ok(err)) => log_error("send", &err.to_string()),
+            (_, Err(err)) => log_error("send", &format!("{err:#}")),
+          }
+          return;
+        }
+      };
+      ctx.activity.lock().await.enter(Phase::Waiting);
+      match (outcome, ctx.declined_tx.as_ref(), ctx.failure_tx.as_ref()) {
+        (SendOutcome::Delivered, _, _) => {
+          if let Some(tx) = ctx.success_tx.as_ref() {
+            let _ = tx.send(send_peer_id);
+          }
+        }
+        (SendOutcome::Declined, Some(tx), _) => {
+          let _ = tx.send(send_peer_id);
+        }
+        (SendOutcome::Declined, None, Some(tx)) => {
+          let _ = tx.send(CliError::Declined("the receiver declined the file".to_string()));
+        }
+        (SendOutcome::Declined, None, None) => {}
+      }
+    })
+  }));
+
+  send_offer(peer.clone(), ctx.signal_tx).await?;
+
+  Ok(peer)
+}
+
+async fn open_offerer_peer(
   peer_id: String,
+  label: &str,
   signal_tx: mpsc::UnboundedSender<ClientMessage>,
-  file_info: Arc<FileInfo>,
-  crypto: Option<Arc<Aes256Gcm>>,
-  success_tx: Option<mpsc::UnboundedSender<()>>,
-) -> Result<Arc<OffererPeer>> {
-  let pc = create_peer_connection().await?;
+  ice: Arc<IceArgs>,
+) -> Result<(Arc<OffererPeer>, Arc<RTCDataChannel>)> {
+  let pc = create_peer_connection(&ice).await?;
   let dc = pc
     .create_data_channel(
-      "file",
+      label,
       Some(RTCDataChannelInit {
         ordered: Some(true),
         ..Default::default()
@@ -545,25 +2247,26 @@ async fn create_offerer_peer(
     .await?;
 
   let peer = Arc::new(OffererPeer {
-    peer_id: peer_id.clone(),
+    peer_id,
     pc: pc.clone(),
+    ice: ice.clone(),
     state: Arc::new(Mutex::new(OffererPeerState {
       signal_sid: 0,
       active_sid: None,
       pending_candidates: Vec::new(),
       remote_desc_set: false,
       sending: false,
+      control_rx: None,
     })),
   });
 
   let peer_clone = peer.clone();
-  let tx = signal_tx.clone();
   pc.on_ice_candidate(Box::new(move |candidate| {
     let peer_clone = peer_clone.clone();
-    let tx = tx.clone();
+    let tx = signal_tx.clone();
+    let ice = ice.clone();
     Box::pin(async move {
-      if let Some(candidate) = candidate {
-        let candidate = candidate.to_json().unwrap_or_default();
+      if let Some(candidate) = candidate.and_then(|candidate| ice.expose(&candidate)) {
         let sid = peer_clone.state.lock().await.active_sid;
         if let Some(sid) = sid {
           let _ = tx.send(ClientMessage::Candidate {
@@ -576,49 +2279,75 @@ async fn create_offerer_peer(
     })
   }));
 
+  let peer_id = peer.peer_id.clone();
   pc.on_peer_connection_state_change(Box::new(move |state: RTCPeerConnectionState| {
+    let peer_id = peer_id.clone();
     Box::pin(async move {
-      log_line("[rtc] connectionState", &format!("{:?}", state));
+      if state == RTCPeerConnectionState::Connected {
+        emit(Event::PeerConnected { peer_id: Some(peer_id) });
+      } else {
+        log_line("[rtc] connectionState", &format!("{:?}", state));
+      }
     })
   }));
 
-  let send_tx = signal_tx.clone();
-  let send_peer_id = peer_id.clone();
-  let file_info = file_info.clone();
-  let send_state = peer.state.clone();
-  let dc_for_open = dc.clone();
-  let crypto = crypto.clone();
-  let success_tx = success_tx.clone();
-  dc.on_open(Box::new(move || {
-    let send_tx = send_tx.clone();
-    let send_peer_id = send_peer_id.clone();
-    let file_info = file_info.clone();
-    let dc = dc_for_open.clone();
-    let send_state = send_state.clone();
-    let crypto = crypto.clone();
-    let success_tx = success_tx.clone();
-    Box::pin(async move {
-      let mut guard = send_state.lock().await;
-      if guard.sending {
-        return;
-      }
-      guard.sending = true;
-      drop(guard);
+  Ok((peer, dc))
+}
 
-      if let Err(err) = send_file(&dc, &file_info, crypto).await {
-        log_line("[send] error", &format!("{err:#}"));
-        return;
-      }
-      let _ = send_tx.send(ClientMessage::TransferDone { peer_id: send_peer_id });
-      if let Some(tx) = success_tx.as_ref() {
-        let _ = tx.send(());
+/// Sends every file of the feed to one peer, until it declines one. `slot` is the peer's ID when
+/// we are the room's offerer; the server counts the peer against `maxConcurrent` until told it
+/// is done.
+async fn send_queued_files(
+  dc: &RTCDataChannel,
+  control_rx: &mut mpsc::UnboundedReceiver<DataMessage>,
+  ctx: &SendContext,
+  max_message_size: Option<usize>,
+  mut slot: Option<&str>,
+) -> Result<SendOutcome> {
+  ctx.activity.lock().await.enter(Phase::Transferring);
+  let (file_tx, mut file_rx) = mpsc::unbounded_channel::<Arc<FileInfo>>();
+  ctx.files.subscribe(file_tx).await;
+  let caps = exchange_hello(dc, control_rx, ctx.sender_name.as_deref()).await?;
+  let frame_size = negotiated_frame_size(max_message_size, &caps)?;
+  log_line("[proto] frame size", &format!("{frame_size} bytes"));
+  let mut queue: VecDeque<Arc<FileInfo>> = VecDeque::new();
+  let mut outcome = SendOutcome::Delivered;
+  loop {
+    // A file rewritten while still queued is only sent once, in its newest form.
+    while let Ok(info) = file_rx.try_recv() {
+      queue.retain(|queued| queued.path != info.path);
+      queue.push_back(info);
+    }
+    let info = match queue.pop_front() {
+      Some(info) => info,
+      None => {
+        if ctx.release_idle {
+          release_slot(ctx, &mut slot);
+        }
+        match file_rx.recv().await {
+          Some(info) => info,
+          None => break,
+        }
       }
-    })
-  }));
-
-  send_offer(peer.clone(), signal_tx).await?;
+    };
+    ctx.activity.lock().await.enter(Phase::Transferring);
+    outcome = send_file(dc, control_rx, &info, ctx.crypto.clone(), &ctx.activity, &caps, frame_size).await?;
+    ctx.activity.lock().await.enter(Phase::Waiting);
+    // A declined file ends the transfer for this receiver.
+    if outcome == SendOutcome::Declined {
+      break;
+    }
+  }
+  release_slot(ctx, &mut slot);
+  Ok(outcome)
+}
 
-  Ok(peer)
+fn release_slot(ctx: &SendContext, slot: &mut Option<&str>) {
+  if let Some(peer_id) = slot.take() {
+    let _ = ctx.signal_tx.send(ClientMessage::TransferDone {
+      peer_id: peer_id.to_string(),
+    });
+  }
 }
 
 async fn send_offer(peer: Arc<OffererPeer>, signal_tx: mpsc::UnboundedSender<ClientMessage>) -> Result<()> {
@@ -633,7 +2362,8 @@ async fn send_offer(peer: Arc<OffererPeer>, signal_tx: mpsc::UnboundedSender<Cli
 
   let offer = peer.pc.create_offer(None).await?;
   peer.pc.set_local_description(offer).await?;
-  if let Some(local) = peer.pc.local_description().await {
+  if let Some(mut local) = peer.pc.local_description().await {
+    local.sdp = peer.ice.filter_sdp(&local.sdp);
     let _ = signal_tx.send(ClientMessage::Offer {
       to: peer.peer_id.clone(),
       sid,
@@ -716,47 +2446,95 @@ async fn flush_receiver_candidates(state: &mut ReceiverState) -> Result<()> {
   Ok(())
 }
 
-async fn wire_receiver_channel(dc: Arc<RTCDataChannel>, progress: Arc<Mutex<ReceiveProgress>>) {
+async fn wire_receiver_channel(
+  dc: Arc<RTCDataChannel>,
+  progress: Arc<Mutex<ReceiveProgress>>,
+  control_tx: Option<mpsc::UnboundedSender<DataMessage>>,
+) {
+  let reply_dc = Arc::downgrade(&dc);
   dc.on_message(Box::new(move |msg: DataChannelMessage| {
     let progress = progress.clone();
+    let reply_dc = reply_dc.clone();
+    let control_tx = control_tx.clone();
     Box::pin(async move {
       if msg.is_string {
         if let Ok(text) = String::from_utf8(msg.data.to_vec()) {
           if let Ok(parsed) = serde_json::from_str::<DataMessage>(&text) {
             match parsed {
-              DataMessage::Meta { name, size, mime, encrypted } => {
+              DataMessage::Hello { version, features, name } => {
+                let caps = Capabilities::negotiate(version, &features);
+                let mut guard = progress.lock().await;
+                guard.peer_caps = caps;
+                guard.sender_name = name.clone();
+                drop(guard);
+                // A peer that also sends (session) runs its own `exchange_hello`, so hand the
+                // hello over instead of replying to it.
+                if let Some(tx) = control_tx.as_ref() {
+                  let _ = tx.send(DataMessage::Hello { version, features, name });
+                } else if let Some(dc) = reply_dc.upgrade() {
+                  log_line("[proto] peer", &progress.lock().await.peer_caps.describe());
+                  if let Ok(hello) = serde_json::to_string(&Capabilities::local_hello(None)) {
+                    let _ = dc.send_text(hello).await;
+                  }
+                }
+              }
+              DataMessage::Meta { name, size, mime, encrypted, snippet } => {
                 let mut guard = progress.lock().await;
+                let consent = guard.peer_caps.supports(FEATURE_CONSENT);
                 if encrypted && guard.crypto.is_none() {
-                  log_line("[recv] error", "encrypted files need a decryption key");
+                  if consent {
+                    send_control(&reply_dc, &decline("no decryption key")).await;
+                  }
+                  guard.fail(CliError::Key("the sender encrypts files; pass the room URL with #k= or --key".to_string()));
                   return;
                 }
-                guard.encrypted = encrypted;
                 let safe_name = sanitize_file_name(&name);
-                let path = guard.output_dir.join(&safe_name);
-                match File::create(&path).await {
-                  Ok(file) => {
-                    guard.current_file = Some(path);
-                    guard.file = Some(file);
-                    guard.expected_size = size;
-                    guard.received = 0;
-                    log_line("[recv] meta", &format!("{safe_name} ({mime}, {size} bytes)"));
-                  }
-                  Err(err) => {
-                    log_line("[recv] error", &format!("{err:#}"));
+                let printed = snippet && guard.saving.print_snippets && size <= SNIPPET_PRINT_LIMIT;
+                let dir = (!printed).then(|| guard.saving.save_dir());
+                if let Some(reason) = guard.screening.policy.refusal(&safe_name, size, &mime, dir.as_deref()) {
+                  if consent {
+                    send_control(&reply_dc, &decline(&reason)).await;
                   }
+                  guard.refuse(&safe_name, reason);
+                  return;
                 }
+                let offer = MetaOffer { name, safe_name, size, mime, encrypted, snippet, printed };
+                // Only a sender that waits for our answer can be asked; one that does not is
+                // already sending the file.
+                if guard.screening.confirm && consent {
+                  drop(guard);
+                  spawn_confirm(progress, reply_dc, offer);
+                  return;
+                }
+                accept_meta(&mut guard, &reply_dc, offer).await;
               }
-              DataMessage::Done => {
+              DataMessage::Done { sha256 } => {
                 let mut guard = progress.lock().await;
-                guard.file = None;
-                guard.encrypted = false;
-                if let Some(tx) = guard.success_tx.take() {
-                  let _ = tx.send(());
+                if let (Some(hasher), Some(expected)) = (guard.hasher.take(), sha256) {
+                  let actual = hex_digest(hasher);
+                  if actual != expected {
+                    let name = match guard.current_file.as_ref() {
+                      Some(path) => path.display().to_string(),
+                      None => guard.file_name.clone(),
+                    };
+                    guard.fail(CliError::Checksum(format!(
+                      "sha256 mismatch for {name} (expected {expected}, got {actual})"
+                    )));
+                    return;
+                  }
+                  log_line("[recv] sha256", &actual);
+                  guard.digest = Some(actual);
                 }
-                if let Some(path) = guard.current_file.take() {
-                  log_line("[recv] completed", &path.display().to_string());
+                finish_receive(&mut guard).await;
+              }
+              // In a session these answer our own `meta`; the sending half is waiting for them.
+              answer @ (DataMessage::Accept | DataMessage::Decline { .. }) => {
+                if let Some(tx) = control_tx.as_ref() {
+                  let _ = tx.send(answer);
                 }
               }
+              // Only `pipe` streams end with `eof`; the file protocol has nothing to close.
+              DataMessage::Eof => {}
             }
           }
         }
@@ -765,87 +2543,500 @@ async fn wire_receiver_channel(dc: Arc<RTCDataChannel>, progress: Arc<Mutex<Rece
 
       let (encrypted, crypto) = {
         let guard = progress.lock().await;
+        // Chunks of an abandoned file keep arriving until the sender finishes; drop them quietly.
+        if guard.file.is_none() && guard.snippet.is_none() {
+          return;
+        }
         (guard.encrypted, guard.crypto.clone())
       };
 
-      let payload = if encrypted {
-        let crypto = match crypto.as_ref() {
-          Some(crypto) => crypto,
-          None => {
-            log_line("[recv] error", "encrypted chunk received without key");
-            return;
-          }
-        };
-        match decrypt_frame(crypto, msg.data.as_ref()) {
+      let payload = match (encrypted, crypto.as_ref()) {
+        (true, Some(crypto)) => match decrypt_frame(crypto, msg.data.as_ref()) {
           Ok(plain) => plain,
-          Err(err) => {
-            log_line("[recv] error", &format!("{err:#}"));
+          Err(_) => {
+            let err = CliError::Key("could not decrypt data from the sender; is the key right?".to_string());
+            progress.lock().await.fail(err);
             return;
           }
-        }
-      } else {
-        msg.data.to_vec()
+        },
+        _ => msg.data.to_vec(),
       };
 
       let mut guard = progress.lock().await;
-      if let Some(file) = guard.file.as_mut() {
-        if file.write_all(&payload).await.is_ok() {
-          guard.received += payload.len() as u64;
-          if guard.expected_size > 0 && guard.received >= guard.expected_size {
-            guard.file = None;
-            guard.encrypted = false;
-            if let Some(tx) = guard.success_tx.take() {
-              let _ = tx.send(());
-            }
-            if let Some(path) = guard.current_file.take() {
-              log_line("[recv] completed", &path.display().to_string());
-            }
-          }
+      guard.activity.lock().await.touch();
+      // The policy and free space checks trusted the announced size, so hold the sender to it.
+      if guard.received + payload.len() as u64 > guard.expected_size {
+        if guard.peer_caps.supports(FEATURE_CONSENT) {
+          send_control(&reply_dc, &decline("more data than announced")).await;
         }
+        let name = match guard.current_file.as_ref() {
+          Some(path) => path.display().to_string(),
+          None => "text snippet".to_string(),
+        };
+        let announced = format_size(guard.expected_size);
+        guard.fail(CliError::Refused(format!("{name}: the sender sent more than the {announced} it announced")));
+        return;
+      }
+      let written = if let Some(snippet) = guard.snippet.as_mut() {
+        snippet.extend_from_slice(&payload);
+        Ok(())
+      } else if let Some(file) = guard.file.as_mut() {
+        file.write_all(&payload).await
+      } else {
+        return;
+      };
+      if let Err(err) = written {
+        let path = guard.current_file.clone().unwrap_or_default();
+        guard.fail(CliError::Storage(format!("write {}: {err}", path.display())));
+        return;
+      }
+      guard.received += payload.len() as u64;
+      if let Some(hasher) = guard.hasher.as_mut() {
+        hasher.update(&payload);
+      }
+      if guard.snippet.is_none() && guard.last_progress.elapsed() >= PROGRESS_INTERVAL {
+        guard.last_progress = Instant::now();
+        emit(Event::Progress {
+          direction: Direction::Receive,
+          name: &guard.file_name,
+          bytes: guard.received,
+          total: guard.expected_size,
+        });
+      }
+      // With a digest to verify, completion waits for `done`; legacy senders finish on size alone.
+      if guard.hasher.is_none() && guard.expected_size > 0 && guard.received >= guard.expected_size {
+        finish_receive(&mut guard).await;
       }
     })
   }));
 }
 
-async fn send_file(dc: &RTCDataChannel, info: &FileInfo, crypto: Option<Arc<Aes256Gcm>>) -> Result<()> {
+/// Opens the file or snippet buffer for `offer` and tells a consent-aware sender to go ahead.
+async fn accept_meta(progress: &mut ReceiveProgress, reply_dc: &Weak<RTCDataChannel>, offer: MetaOffer) {
+  let consent = progress.peer_caps.supports(FEATURE_CONSENT);
+  progress.encrypted = offer.encrypted;
+  if offer.printed {
+    progress.snippet = Some(Vec::with_capacity(offer.size as usize));
+    progress.expected_size = offer.size;
+    progress.received = 0;
+    progress.last_progress = Instant::now();
+    progress.hasher = progress.peer_caps.supports(FEATURE_SHA256).then(Sha256::new);
+    progress.activity.lock().await.enter_receiver(ReceiverStatus::Transferring);
+    log_line("[recv] meta", &format!("text snippet ({} bytes)", offer.size));
+    if consent {
+      send_control(reply_dc, &DataMessage::Accept).await;
+    }
+    return;
+  }
+  let path = match progress.saving.target_path(&offer.safe_name, &progress.sender_label()).await {
+    Ok(path) => path,
+    Err(err) => {
+      if consent {
+        send_control(reply_dc, &decline("the receiver cannot store the file")).await;
+      }
+      progress.fail(CliError::Storage(format!("{err:#}")));
+      return;
+    }
+  };
+  match File::create(&path).await {
+    Ok(file) => {
+      log_line("[recv] meta", &format!("{} ({}, {} bytes)", offer.safe_name, offer.mime, offer.size));
+      if path.file_name().and_then(|name| name.to_str()) != Some(offer.safe_name.as_str()) {
+        log_line("[recv] saving as", &path.display().to_string());
+      }
+      progress.current_file = Some(path);
+      progress.file = Some(file);
+      progress.file_name = offer.name;
+      progress.mime = offer.mime;
+      progress.digest = None;
+      progress.expected_size = offer.size;
+      progress.received = 0;
+      progress.last_progress = Instant::now();
+      progress.hasher = progress.peer_caps.supports(FEATURE_SHA256).then(Sha256::new);
+      progress.activity.lock().await.enter_receiver(ReceiverStatus::Transferring);
+      if consent {
+        send_control(reply_dc, &DataMessage::Accept).await;
+      }
+    }
+    Err(err) => {
+      if consent {
+        send_control(reply_dc, &decline("the receiver cannot store the file")).await;
+      }
+      progress.fail(CliError::Storage(format!("create {}: {err}", path.display())));
+    }
+  }
+}
+
+async fn finish_receive(progress: &mut ReceiveProgress) {
+  // tokio completes writes on a blocking thread; flush so hooks and hashes see every byte.
+  if let Some(mut file) = progress.file.take() {
+    if let Err(err) = file.flush().await {
+      let path = progress.current_file.clone().unwrap_or_default();
+      progress.fail(CliError::Storage(format!("write {}: {err}", path.display())));
+      return;
+    }
+  }
+  progress.encrypted = false;
+  if let Some(snippet) = progress.snippet.take() {
+    log_line("[recv] text", &format!("{} bytes", snippet.len()));
+    emit(Event::Snippet {
+      text: &String::from_utf8_lossy(&snippet),
+    });
+  }
+  progress.activity.lock().await.enter_receiver(ReceiverStatus::Done);
+  // The hook is queued before success is reported so `receive` waits for it before exiting.
+  if let Some(path) = progress.current_file.take() {
+    emit(Event::FileCompleted {
+      direction: Direction::Receive,
+      name: &progress.file_name,
+      path: Some(&path),
+      size: progress.received,
+      sha256: progress.digest.as_deref(),
+    });
+    progress.record(path.clone(), TransferStatus::Completed);
+    if let Some(tx) = progress.reports.exec_tx.as_ref() {
+      let _ = tx.send(ReceivedFile {
+        path,
+        name: progress.file_name.clone(),
+        mime: progress.mime.clone(),
+        size: progress.received,
+        sha256: progress.digest.take(),
+        peer_id: progress.sender.clone(),
+        sender_name: progress.sender_name.clone(),
+      });
+    }
+  }
+  if let Some(tx) = progress.success_tx.take() {
+    let _ = tx.send(());
+  }
+}
+
+async fn hash_file(path: &Path) -> Result<String> {
+  let mut file = File::open(path).await.with_context(|| format!("open {}", path.display()))?;
+  let mut hasher = Sha256::new();
+  let mut buffer = vec![0u8; 64 * 1024];
+  loop {
+    let read = file.read(&mut buffer).await?;
+    if read == 0 {
+      break;
+    }
+    hasher.update(&buffer[..read]);
+  }
+  Ok(hex_digest(hasher))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SendOutcome {
+  Delivered,
+  Declined,
+}
+
+async fn send_file(
+  dc: &RTCDataChannel,
+  control_rx: &mut mpsc::UnboundedReceiver<DataMessage>,
+  info: &FileInfo,
+  crypto: Option<Arc<Aes256Gcm>>,
+  activity: &Mutex<Activity>,
+  caps: &Capabilities,
+  frame_size: usize,
+) -> Result<SendOutcome> {
   let encrypted = crypto.is_some();
-  let meta = serde_json::json!({
-    "type": "meta",
-    "name": info.name,
-    "size": info.size,
-    "mime": info.mime,
-    "encrypted": encrypted,
-  });
-  let meta_text = serde_json::to_string(&meta)?;
-  dc.send_text(meta_text).await?;
+  let meta = DataMessage::Meta {
+    name: info.name.clone(),
+    size: info.size,
+    mime: info.mime.clone(),
+    encrypted,
+    snippet: info.inline.is_some(),
+  };
+  dc.send_text(serde_json::to_string(&meta)?).await?;
+  if caps.supports(FEATURE_CONSENT) {
+    activity.lock().await.enter(Phase::Waiting);
+    if let Some(reason) = wait_for_consent(control_rx, &info.name, CONSENT_TIMEOUT).await? {
+      log_line("[send] declined", &format!("{} ({reason})", info.name));
+      return Ok(SendOutcome::Declined);
+    }
+    activity.lock().await.enter(Phase::Transferring);
+  }
 
-  let chunk_size = if encrypted {
-    MAX_FRAME_SIZE - AES_NONCE_LEN - AES_TAG_LEN
-  } else {
-    MAX_FRAME_SIZE
+  let chunk_size = frame_chunk_size(frame_size, encrypted);
+  let storage_error = |err: std::io::Error| CliError::Storage(format!("read {}: {err}", info.path.display()));
+  let mut file: Box<dyn tokio::io::AsyncRead + Unpin + Send> = match info.inline.as_ref() {
+    Some(data) => Box::new(std::io::Cursor::new(data.clone())),
+    None => Box::new(File::open(&info.path).await.map_err(storage_error)?),
   };
-  let mut file = File::open(&info.path).await?;
   let mut buffer = vec![0u8; chunk_size];
+  let mut hasher = caps.supports(FEATURE_SHA256).then(Sha256::new);
+  let mut sent = 0u64;
+  let mut last_progress = Instant::now();
   loop {
-    let read = file.read(&mut buffer).await?;
+    let read = file.read(&mut buffer).await.map_err(storage_error)?;
     if read == 0 {
       break;
     }
+    sent += read as u64;
+    if let Some(hasher) = hasher.as_mut() {
+      hasher.update(&buffer[..read]);
+    }
     let payload = if let Some(crypto) = crypto.as_ref() {
       Bytes::from(encrypt_frame(crypto, &buffer[..read])?)
     } else {
       Bytes::copy_from_slice(&buffer[..read])
     };
     dc.send(&payload).await?;
+    activity.lock().await.touch();
+    // A receiver that gives up mid-file says so; the rest would only be dropped.
+    if let Some(reason) = declined_mid_file(control_rx) {
+      log_line("[send] declined", &format!("{} ({reason})", info.name));
+      return Ok(SendOutcome::Declined);
+    }
+    if last_progress.elapsed() >= PROGRESS_INTERVAL {
+      last_progress = Instant::now();
+      emit(Event::Progress {
+        direction: Direction::Send,
+        name: &info.name,
+        bytes: sent,
+        total: info.size,
+      });
+    }
   }
 
-  dc.send_text("{\"type\":\"done\"}").await?;
+  let sha256 = hasher.map(hex_digest);
+  let done = DataMessage::Done { sha256: sha256.clone() };
+  dc.send_text(serde_json::to_string(&done)?).await?;
   wait_for_drain(dc).await;
-  Ok(())
+  emit(Event::FileCompleted {
+    direction: Direction::Send,
+    name: &info.name,
+    path: info.inline.is_none().then_some(info.path.as_path()),
+    size: sent,
+    sha256: sha256.as_deref(),
+  });
+  Ok(SendOutcome::Delivered)
+}
+
+async fn exchange_hello(
+  dc: &RTCDataChannel,
+  control_rx: &mut mpsc::UnboundedReceiver<DataMessage>,
+  name: Option<&str>,
+) -> Result<Capabilities> {
+  dc.send_text(serde_json::to_string(&Capabilities::local_hello(name))?).await?;
+  let caps = await_hello(control_rx, HELLO_TIMEOUT).await;
+  log_line("[proto] peer", &caps.describe());
+  Ok(caps)
+}
+
+/// The capabilities from the peer's `hello`. Browsers ignore unknown messages and never answer,
+/// so silence for `limit` means the legacy protocol.
+async fn await_hello(control_rx: &mut mpsc::UnboundedReceiver<DataMessage>, limit: Duration) -> Capabilities {
+  let reply = tokio::time::timeout(limit, async {
+    while let Some(msg) = control_rx.recv().await {
+      if let DataMessage::Hello { version, features, .. } = msg {
+        return Some(Capabilities::negotiate(version, &features));
+      }
+    }
+    None
+  })
+  .await
+  .ok()
+  .flatten();
+  reply.unwrap_or_default()
+}
+
+impl Capabilities {
+  fn local_hello(name: Option<&str>) -> DataMessage {
+    DataMessage::Hello {
+      version: PROTOCOL_VERSION,
+      features: SUPPORTED_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+      name: name.map(|name| name.to_string()),
+    }
+  }
+
+  fn negotiate(version: u32, features: &[String]) -> Self {
+    Self {
+      version: version.min(PROTOCOL_VERSION),
+      features: features
+        .iter()
+        .filter(|feature| SUPPORTED_FEATURES.contains(&feature.as_str()))
+        .cloned()
+        .collect(),
+    }
+  }
+
+  fn supports(&self, feature: &str) -> bool {
+    self.features.iter().any(|value| value == feature)
+  }
+
+  fn describe(&self) -> String {
+    if self.version == 0 {
+      return "legacy (meta/chunks/done)".to_string();
+    }
+    format!("v{} [{}]", self.version, self.features.join(", "))
+  }
+}
+
+fn hex_digest(hasher: Sha256) -> String {
+  hasher
+    .finalize()
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
+impl FileFeed {
+  async fn subscribe(&self, tx: mpsc::UnboundedSender<Arc<FileInfo>>) {
+    match self {
+      // Dropping the sender right away ends the peer's queue after this one file.
+      FileFeed::Single(info) => {
+        let _ = tx.send(info.clone());
+      }
+      FileFeed::Shared(feed) => feed.lock().await.subscribe(tx),
+    }
+  }
+}
+
+impl SharedFeed {
+  fn subscribe(&mut self, tx: mpsc::UnboundedSender<Arc<FileInfo>>) {
+    for info in &self.published {
+      let _ = tx.send(info.clone());
+    }
+    self.subscribers.push(tx);
+  }
+
+  fn publish(&mut self, info: FileInfo) {
+    let info = Arc::new(info);
+    self.published.retain(|published| published.path != info.path);
+    self.published.push(info.clone());
+    self.subscribers.retain(|tx| tx.send(info.clone()).is_ok());
+  }
+
+  fn retract(&mut self, path: &Path) {
+    self.published.retain(|published| published.path != path);
+  }
+}
+
+async fn spawn_watcher(dir: PathBuf) -> Result<Arc<Mutex<SharedFeed>>> {
+  // Files already present are the baseline; only later additions and changes are sent.
+  let seen = scan_watch_dir(&dir)
+    .await
+    .with_context(|| format!("watch {}", dir.display()))?;
+  log_line("[watch] directory", &format!("{} ({} existing files ignored)", dir.display(), seen.len()));
+  let mut state = WatchState {
+    seen,
+    pending: HashMap::new(),
+  };
+  let feed = Arc::new(Mutex::new(SharedFeed::default()));
+  let watch_feed = feed.clone();
+  tokio::spawn(async move {
+    let mut ticker = interval(WATCH_POLL_INTERVAL);
+    loop {
+      ticker.tick().await;
+      let current = match scan_watch_dir(&dir).await {
+        Ok(current) => current,
+        Err(err) => {
+          log_error("watch", &format!("{err:#}"));
+          continue;
+        }
+      };
+      let (removed, settled) = state.update(current, Instant::now());
+      for path in removed {
+        watch_feed.lock().await.retract(&path);
+      }
+      for path in settled {
+        match load_file_info(&path).await {
+          Ok(info) => {
+            log_line("[watch] queued", &format!("{} ({} bytes)", info.name, info.size));
+            watch_feed.lock().await.publish(info);
+          }
+          Err(err) => log_error("watch", &format!("{}: {err:#}", path.display())),
+        }
+      }
+    }
+  });
+  Ok(feed)
+}
+
+impl DownloadTally {
+  /// Counts a receiver that got everything; true once the share has served as many as it should.
+  fn finish(&mut self, peer_id: String, at: String) -> bool {
+    if self.finished.insert(peer_id.clone()) {
+      self.downloads.push((peer_id, at));
+    }
+    self.limit.is_some_and(|limit| self.finished.len() >= limit)
+  }
+
+  /// Whether `--max-downloads` leaves no room for another receiver. Those still transferring
+  /// count against it, so it is never overshot; those that declined do not.
+  fn quota_reached<'a>(&self, connected: impl Iterator<Item = &'a String>) -> bool {
+    let Some(quota) = self.quota else { return false };
+    let in_flight = connected
+      .filter(|id| !self.finished.contains(*id) && !self.declined.contains(*id))
+      .count();
+    self.finished.len() + in_flight >= quota
+  }
+}
+
+impl WatchState {
+  /// Takes in one scan of the directory, returning the files gone since the last one and the
+  /// files that are ready to send.
+  fn update(&mut self, current: HashMap<PathBuf, (SystemTime, u64)>, now: Instant) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let removed: Vec<PathBuf> = self.seen.keys().filter(|path| !current.contains_key(*path)).cloned().collect();
+    for path in &removed {
+      self.seen.remove(path);
+    }
+    self.pending.retain(|path, _| current.contains_key(path));
+    let mut settled = Vec::new();
+    for (path, stamp) in current {
+      if self.seen.get(&path) == Some(&stamp) {
+        self.pending.remove(&path);
+        continue;
+      }
+      // A file is only sent once its size and mtime have stopped changing, so writers
+      // that are still flushing it do not produce truncated copies.
+      match self.pending.get(&path) {
+        Some((pending_stamp, since)) if *pending_stamp == stamp => {
+          if now.duration_since(*since) < WATCH_SETTLE_TIME {
+            continue;
+          }
+        }
+        _ => {
+          self.pending.insert(path, (stamp, now));
+          continue;
+        }
+      }
+      self.pending.remove(&path);
+      self.seen.insert(path.clone(), stamp);
+      settled.push(path);
+    }
+    (removed, settled)
+  }
+}
+
+async fn scan_watch_dir(dir: &Path) -> Result<HashMap<PathBuf, (SystemTime, u64)>> {
+  let mut entries = tokio::fs::read_dir(dir).await?;
+  let mut files = HashMap::new();
+  while let Some(entry) = entries.next_entry().await? {
+    // Dotfiles are usually editor swap files or in-progress downloads.
+    if entry.file_name().to_string_lossy().starts_with('.') {
+      continue;
+    }
+    let Ok(metadata) = entry.metadata().await else { continue };
+    if !metadata.is_file() {
+      continue;
+    }
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    files.insert(entry.path(), (modified, metadata.len()));
+  }
+  Ok(files)
 }
 
 async fn load_file_info(path: &Path) -> Result<FileInfo> {
-  let metadata = tokio::fs::metadata(path).await?;
+  let storage = |err: std::io::Error| CliError::Storage(format!("read {}: {err}", path.display()));
+  let metadata = tokio::fs::metadata(path).await.map_err(storage)?;
+  if !metadata.is_file() {
+    return Err(CliError::Storage(format!("{} is not a regular file", path.display())).into());
+  }
+  // Fail now rather than after a peer has connected.
+  File::open(path).await.map_err(storage)?;
   let size = metadata.len();
   let name = path
     .file_name()
@@ -861,10 +3052,134 @@ async fn load_file_info(path: &Path) -> Result<FileInfo> {
     name,
     size,
     mime,
+    inline: None,
+  })
+}
+
+async fn snippet_file_info(text: String) -> Result<FileInfo> {
+  let data = if text == "-" {
+    let mut data = Vec::new();
+    tokio::io::stdin().read_to_end(&mut data).await.context("read text from stdin")?;
+    data
+  } else {
+    text.into_bytes()
+  };
+  if data.is_empty() {
+    return Err(anyhow!("Text snippet is empty"));
+  }
+  let name = format!("snippet-{}.txt", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+  Ok(FileInfo {
+    path: PathBuf::from(&name),
+    name,
+    size: data.len() as u64,
+    mime: "text/plain".to_string(),
+    inline: Some(Bytes::from(data)),
   })
 }
 
-async fn create_peer_connection() -> Result<Arc<RTCPeerConnection>> {
+impl IceArgs {
+  fn servers(&self) -> Vec<RTCIceServer> {
+    if self.ice_servers.is_empty() {
+      return vec![RTCIceServer {
+        urls: vec![DEFAULT_STUN_URL.to_string()],
+        ..Default::default()
+      }];
+    }
+    self
+      .ice_servers
+      .iter()
+      .map(|url| {
+        let mut server = RTCIceServer {
+          urls: vec![url.clone()],
+          ..Default::default()
+        };
+        if url.starts_with("turn:") || url.starts_with("turns:") {
+          server.username = self.turn_username.clone().unwrap_or_default();
+          server.credential = self.turn_credential.clone().unwrap_or_default();
+        }
+        server
+      })
+      .collect()
+  }
+
+  fn allows_type(&self, typ: RTCIceCandidateType) -> bool {
+    match typ {
+      RTCIceCandidateType::Relay => true,
+      RTCIceCandidateType::Host => !self.relay_only && !self.no_host_candidates,
+      _ => !self.relay_only,
+    }
+  }
+
+  /// The candidate as the peer may see it, or `None` if the policy keeps it back.
+  fn expose(&self, candidate: &RTCIceCandidate) -> Option<RTCIceCandidateInit> {
+    if !self.allows_type(candidate.typ) {
+      log_line(
+        "[ice] dropped",
+        &format!("{} candidate {}:{}", candidate.typ, candidate.address, candidate.port),
+      );
+      return None;
+    }
+    let mut init = candidate.to_json().unwrap_or_default();
+    init.candidate = self.mask_related_address(&init.candidate);
+    Some(init)
+  }
+
+  /// Applies the policy to every candidate a description carries, in trickle mode too, where
+  /// the ones gathered so far are already inline.
+  fn filter_sdp(&self, sdp: &str) -> String {
+    sdp
+      .split_inclusive('\n')
+      .filter(|line| !line.starts_with("a=candidate:") || self.exposes_sdp_candidate(line))
+      .map(|line| {
+        if line.starts_with("a=candidate:") {
+          self.mask_related_address(line)
+        } else {
+          line.to_string()
+        }
+      })
+      .collect()
+  }
+
+  /// srflx and relay candidates name the address they were derived from in `raddr`/`rport`,
+  /// which is the LAN address; blank it whenever host candidates are hidden.
+  fn mask_related_address(&self, line: &str) -> String {
+    if self.allows_type(RTCIceCandidateType::Host) {
+      return line.to_string();
+    }
+    let body = line.trim_end_matches(['\r', '\n']);
+    let mut related = None;
+    let fields: Vec<&str> = body
+      .split(' ')
+      .map(|field| {
+        let value = related.take().unwrap_or(field);
+        related = match field {
+          "raddr" => Some("0.0.0.0"),
+          "rport" => Some("0"),
+          _ => None,
+        };
+        value
+      })
+      .collect();
+    format!("{}{}", fields.join(" "), &line[body.len()..])
+  }
+
+  fn exposes_sdp_candidate(&self, line: &str) -> bool {
+    let mut fields = line.split_whitespace();
+    let typ = fields
+      .by_ref()
+      .skip_while(|field| *field != "typ")
+      .nth(1)
+      .map(RTCIceCandidateType::from)
+      .unwrap_or_default();
+    if self.allows_type(typ) {
+      return true;
+    }
+    log_line("[ice] dropped", &format!("{typ} candidate from SDP"));
+    false
+  }
+}
+
+async fn create_peer_connection(ice: &IceArgs) -> Result<Arc<RTCPeerConnection>> {
   let mut media_engine = MediaEngine::default();
   media_engine.register_default_codecs()?;
 
@@ -877,10 +3192,12 @@ async fn create_peer_connection() -> Result<Arc<RTCPeerConnection>> {
     .build();
 
   let config = RTCConfiguration {
-    ice_servers: vec![RTCIceServer {
-      urls: vec!["stun:stun.cloudflare.com:3478".to_string()],
-      ..Default::default()
-    }],
+    ice_servers: ice.servers(),
+    ice_transport_policy: if ice.relay_only {
+      RTCIceTransportPolicy::Relay
+    } else {
+      RTCIceTransportPolicy::All
+    },
     ..Default::default()
   };
 
@@ -934,14 +3251,20 @@ fn extract_room_id_from_url(url: &Url) -> Result<String> {
 fn parse_key_fragment(fragment: &str) -> Result<Option<Vec<u8>>> {
   for (key, value) in form_urlencoded::parse(fragment.as_bytes()) {
     if key == "k" {
-      return Ok(Some(b64url_decode(&value)?));
+      return Ok(Some(decode_key(&value)?));
     }
   }
   Ok(None)
 }
 
-fn build_crypto(key: &[u8]) -> Result<Aes256Gcm> {
-  Aes256Gcm::new_from_slice(key).map_err(|_| anyhow!("Invalid encryption key length"))
+fn decode_key(value: &str) -> Result<Vec<u8>, CliError> {
+  URL_SAFE_NO_PAD
+    .decode(value)
+    .map_err(|err| CliError::Key(format!("not valid base64url ({err})")))
+}
+
+fn build_crypto(key: &[u8]) -> Result<Aes256Gcm, CliError> {
+  Aes256Gcm::new_from_slice(key).map_err(|_| CliError::Key(format!("expected {AES_KEY_LEN} bytes, got {}", key.len())))
 }
 
 fn generate_key() -> Result<[u8; AES_KEY_LEN]> {
@@ -950,6 +3273,48 @@ fn generate_key() -> Result<[u8; AES_KEY_LEN]> {
   Ok(key)
 }
 
+fn generate_room_id() -> Result<String> {
+  // Same alphabet as the server's generateRoomId, so CLI-chosen rooms look like any other.
+  const ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+  let mut bytes = [0u8; 10];
+  getrandom(&mut bytes).map_err(|err| anyhow!(err))?;
+  Ok(bytes.iter().map(|byte| ALPHABET[*byte as usize % ALPHABET.len()] as char).collect())
+}
+
+fn frame_chunk_size(frame_size: usize, encrypted: bool) -> usize {
+  if encrypted {
+    frame_size - AES_NONCE_LEN - AES_TAG_LEN
+  } else {
+    frame_size
+  }
+}
+
+/// The `a=max-message-size` the peer put in its session description (RFC 8841).
+async fn remote_max_message_size(pc: &Weak<RTCPeerConnection>) -> Option<usize> {
+  let desc = pc.upgrade()?.remote_description().await?;
+  desc
+    .sdp
+    .lines()
+    .find_map(|line| line.trim().strip_prefix("a=max-message-size:")?.trim().parse().ok())
+}
+
+/// Frame size for `send_file`. Larger frames mean fewer messages and less per-message overhead,
+/// but only a peer that said hello as a CLI gets them; browsers keep the safe default even when
+/// they advertise more. Nobody gets more than the `a=max-message-size` they advertised.
+fn negotiated_frame_size(max_message_size: Option<usize>, caps: &Capabilities) -> Result<usize> {
+  let ceiling = if caps.version > 0 { MAX_FRAME_SIZE } else { DEFAULT_FRAME_SIZE };
+  Ok(match max_message_size {
+    // 0 means the peer takes messages of any size. The webrtc crate advertises nothing, but
+    // another CLI reads whole 64 KiB messages.
+    Some(0) | None => ceiling,
+    // An encrypted frame still needs room for data.
+    Some(limit) if limit <= AES_NONCE_LEN + AES_TAG_LEN => {
+      return Err(anyhow!("The peer only accepts messages of up to {limit} bytes, too small for file data"));
+    }
+    Some(limit) => limit.min(ceiling),
+  })
+}
+
 fn encrypt_frame(crypto: &Aes256Gcm, plain: &[u8]) -> Result<Vec<u8>> {
   let mut nonce_bytes = [0u8; AES_NONCE_LEN];
   getrandom(&mut nonce_bytes).map_err(|err| anyhow!(err))?;
@@ -978,6 +3343,46 @@ fn b64url_decode(value: &str) -> Result<Vec<u8>> {
   URL_SAFE_NO_PAD.decode(value).map_err(|err| anyhow!(err))
 }
 
+/// Fills `{name}`, `{stem}`, `{ext}`, `{date}`, `{time}` and `{sender}` in a `--name-template`.
+fn expand_name_template(template: &str, name: &str, sender: &str) -> Result<String, String> {
+  let path = Path::new(name);
+  let now = chrono::Local::now();
+  let mut expanded = String::new();
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    expanded.push_str(&rest[..start]);
+    let len = rest[start..]
+      .find('}')
+      .ok_or_else(|| format!("unclosed '{{' in name template '{template}'"))?;
+    let value = match &rest[start + 1..start + len] {
+      "name" => name.to_string(),
+      "stem" => path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default(),
+      "ext" => path.extension().map(|ext| ext.to_string_lossy().into_owned()).unwrap_or_default(),
+      "date" => now.format("%Y-%m-%d").to_string(),
+      "time" => now.format("%H%M%S").to_string(),
+      "sender" => sender.to_string(),
+      key => {
+        return Err(format!(
+          "unknown placeholder '{{{key}}}' (expected {{name}}, {{stem}}, {{ext}}, {{date}}, {{time}} or {{sender}})"
+        ))
+      }
+    };
+    // Values come from the sender, so they must not reach outside the output directory.
+    expanded.push_str(&value.replace(['/', '\\'], "_"));
+    rest = &rest[start + len + 1..];
+  }
+  expanded.push_str(rest);
+  Ok(expanded)
+}
+
+fn parse_name_template(value: &str) -> Result<String, String> {
+  if value.contains(['/', '\\']) {
+    return Err("a name template is a file name; use --output-dir or --sort-by for folders".to_string());
+  }
+  expand_name_template(value, "file.bin", "sender")?;
+  Ok(value.to_string())
+}
+
 fn sanitize_file_name(name: &str) -> String {
   let candidate = Path::new(name)
     .file_name()
@@ -1038,6 +3443,7 @@ fn base_endpoint_url(endpoint: Option<&str>) -> Result<Url> {
   let endpoint = endpoint
     .map(|value| value.to_string())
     .or(env_endpoint)
+    .or_else(|| profile().endpoint.clone())
     .unwrap_or_else(|| default_endpoint.to_string());
 
   let mut url = Url::parse(&endpoint)?;
@@ -1054,11 +3460,18 @@ fn base_endpoint_url(endpoint: Option<&str>) -> Result<Url> {
   Ok(url)
 }
 
-async fn create_room(endpoint: Option<&str>, creator_cid: Option<&str>) -> Result<String> {
+async fn create_room(
+  endpoint: Option<&str>,
+  proxy: Option<&str>,
+  creator_cid: Option<&str>,
+  max_concurrent: Option<u32>,
+) -> Result<String> {
   #[derive(Serialize)]
   struct RoomRequest {
     #[serde(rename = "creatorCid", skip_serializing_if = "Option::is_none")]
     creator_cid: Option<String>,
+    #[serde(rename = "maxConcurrent", skip_serializing_if = "Option::is_none")]
+    max_concurrent: Option<u32>,
   }
 
   #[derive(Deserialize)]
@@ -1069,21 +3482,612 @@ async fn create_room(endpoint: Option<&str>, creator_cid: Option<&str>) -> Resul
 
   let mut url = base_endpoint_url(endpoint)?;
   url.set_path("/api/rooms");
-  let client = reqwest::Client::new();
+  let proxy_failed = |err: reqwest::Error| CliError::Signaling(format!("create room: {err}"));
+  let client = match resolve_proxy(proxy, &url)? {
+    Some(proxy) => reqwest::Client::builder().proxy(reqwest::Proxy::all(proxy.as_str()).map_err(proxy_failed)?),
+    None => reqwest::Client::builder().no_proxy(),
+  }
+  .build()
+  .map_err(proxy_failed)?;
   let response = client
     .post(url)
     .json(&RoomRequest {
       creator_cid: creator_cid.map(|value| value.to_string()),
+      max_concurrent,
     })
     .send()
     .await
-    .context("create room request")?;
-  let response = response.error_for_status().context("create room response")?;
-  let body: RoomResponse = response.json().await.context("parse room response")?;
+    .map_err(|err| CliError::Signaling(format!("create room: {err}")))?;
+  if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+    return Err(CliError::RateLimited.into());
+  }
+  let response = response
+    .error_for_status()
+    .map_err(|err| CliError::Signaling(format!("create room: {err}")))?;
+  let body: RoomResponse = response
+    .json()
+    .await
+    .map_err(|err| CliError::Signaling(format!("parse room response: {err}")))?;
   Ok(body.room_id)
 }
 
-fn log_line(label: &str, value: &str) {
-  let now = chrono::Utc::now().format("%H:%M:%S%.3f");
-  println!("[{now}] {label}: {value}");
+type SignalingStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect_signaling(ws_url: &Url, proxy: Option<&str>) -> Result<SignalingStream> {
+  // Unbracketed, as `host_str` would give `[::1]` for IPv6 literals.
+  let host = match ws_url.host() {
+    Some(url::Host::Ipv6(addr)) => addr.to_string(),
+    Some(host) => host.to_string(),
+    None => return Err(anyhow!("Signaling URL has no host")),
+  };
+  let host = host.as_str();
+  let port = ws_url
+    .port_or_known_default()
+    .ok_or_else(|| anyhow!("Signaling URL has no port"))?;
+  let stream = match resolve_proxy(proxy, ws_url)? {
+    Some(proxy) => {
+      log_line("[ws] proxy", &format!("{}://{}", proxy.scheme(), proxy_authority(&proxy)?));
+      match proxy.scheme() {
+        "http" => connect_via_http_proxy(&proxy, host, port).await?,
+        _ => connect_via_socks5(&proxy, host, port).await?,
+      }
+    }
+    None => TcpStream::connect((host, port))
+      .await
+      .map_err(|err| CliError::Signaling(format!("connect to {host}:{port}: {err}")))?,
+  };
+  let (ws_stream, _) = client_async_tls(ws_url.as_str(), stream)
+    .await
+    .map_err(CliError::websocket)?;
+  Ok(ws_stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn saved_room(key: Option<&str>) -> SavedRoom {
+    SavedRoom {
+      room_id: "ROOM".to_string(),
+      creator_cid: "cid".to_string(),
+      endpoint: Some("https://example.com".to_string()),
+      key: key.map(str::to_string),
+      created_at: "2026-01-01T00:00:00+00:00".to_string(),
+    }
+  }
+
+  #[test]
+  fn saved_rooms_keep_their_key_and_endpoint() {
+    let key = b64url_encode(&[7u8; AES_KEY_LEN]);
+    let mut saved = SavedRooms::default();
+    saved.rooms.insert("team".to_string(), saved_room(Some(&key)));
+    let saved: SavedRooms = serde_json::from_str(&serde_json::to_string(&saved).unwrap()).unwrap();
+    let room = saved.get("team").unwrap();
+    assert_eq!(room.url().unwrap(), format!("https://example.com/r/ROOM#k={key}"));
+    assert_eq!(room.send_key("team", true).unwrap(), Some(vec![7u8; AES_KEY_LEN]));
+    assert_eq!(room.send_key("team", false).unwrap(), None);
+    assert!(SavedRooms::default().get("team").is_err());
+  }
+
+  #[test]
+  fn unencrypted_saved_rooms_need_no_encrypt() {
+    let room = saved_room(None);
+    let err = room.send_key("open", true).unwrap_err();
+    assert_eq!(exit_code(&err), 7);
+    assert_eq!(room.send_key("open", false).unwrap(), None);
+    assert_eq!(room.url().unwrap(), "https://example.com/r/ROOM");
+  }
+
+  #[test]
+  fn parse_size_accepts_binary_units() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("4K"), Ok(4 * 1024));
+    assert_eq!(parse_size("500M"), Ok(500 << 20));
+    assert_eq!(parse_size(" 2gb "), Ok(2 << 30));
+    assert_eq!(parse_size("1TiB"), Ok(1 << 40));
+  }
+
+  #[test]
+  fn parse_size_rejects_bad_input() {
+    assert!(parse_size("").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("1.5G").is_err());
+    assert!(parse_size("10X").is_err());
+    assert!(parse_size("99999999999T").is_err());
+  }
+
+  #[test]
+  fn glob_match_handles_wildcards() {
+    assert!(glob_match("image/*", "image/png"));
+    assert!(glob_match("IMAGE/*", "image/PNG"));
+    assert!(glob_match("application/x-ms*", "application/x-msdownload"));
+    assert!(glob_match("*/*ml", "text/html"));
+    assert!(glob_match("text/?tml", "text/html"));
+    assert!(glob_match("*", ""));
+    assert!(!glob_match("image/*", "video/mp4"));
+    assert!(!glob_match("text/?tml", "text/xhtml"));
+    assert!(!glob_match("image/png", "image/png+extra"));
+  }
+
+  #[test]
+  fn refusal_checks_size_extension_and_type() {
+    let policy = PolicyArgs {
+      max_size: Some(1024),
+      allow_ext: vec![".pdf".to_string(), "JPG".to_string()],
+      ..PolicyArgs::default()
+    };
+    assert_eq!(policy.refusal("a.pdf", 1024, "application/pdf", None), None);
+    assert_eq!(policy.refusal("photo.jpg", 10, "image/jpeg", None), None);
+    assert!(policy.refusal("a.pdf", 1025, "application/pdf", None).is_some());
+    assert!(policy.refusal("notes.txt", 10, "text/plain", None).is_some());
+    assert!(policy.refusal("Makefile", 10, "", None).is_some());
+  }
+
+  #[test]
+  fn refusal_checks_the_type_the_extension_implies() {
+    let deny = PolicyArgs {
+      deny_mime: vec!["application/*".to_string()],
+      ..PolicyArgs::default()
+    };
+    assert_eq!(deny.refusal("cat.png", 10, "image/png", None), None);
+    assert!(deny.refusal("setup.exe", 10, "image/png", None).is_some());
+    assert!(deny.refusal("blob", 10, "", None).is_some());
+    let allow = PolicyArgs {
+      allow_mime: vec!["image/*".to_string()],
+      ..PolicyArgs::default()
+    };
+    assert_eq!(allow.refusal("cat.png", 10, "image/png", None), None);
+    assert!(allow.refusal("cat.png", 10, "text/plain", None).is_some());
+    assert!(allow.refusal("notes.txt", 10, "image/png", None).is_some());
+  }
+
+  fn hello(version: u32, features: &[&str]) -> DataMessage {
+    DataMessage::Hello {
+      version,
+      features: features.iter().map(|feature| feature.to_string()).collect(),
+      name: None,
+    }
+  }
+
+  #[tokio::test]
+  async fn hello_keeps_only_features_both_sides_support() {
+    let (tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    tx.send(hello(1, &[FEATURE_SHA256, FEATURE_CONSENT])).unwrap();
+    let caps = await_hello(&mut rx, HELLO_TIMEOUT).await;
+    assert_eq!(caps.version, 1);
+    assert!(caps.supports(FEATURE_SHA256) && caps.supports(FEATURE_CONSENT));
+
+    // Some of ours, plus one we have never heard of, from a newer version.
+    tx.send(DataMessage::Accept).unwrap();
+    tx.send(hello(9, &["zstd", FEATURE_SHA256])).unwrap();
+    let caps = await_hello(&mut rx, HELLO_TIMEOUT).await;
+    assert_eq!(caps.version, PROTOCOL_VERSION);
+    assert_eq!(caps.features, [FEATURE_SHA256]);
+    assert!(!caps.supports(FEATURE_CONSENT));
+  }
+
+  #[tokio::test]
+  async fn a_peer_that_never_says_hello_gets_the_legacy_protocol() {
+    let (_tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    let started = Instant::now();
+    let caps = await_hello(&mut rx, HELLO_TIMEOUT).await;
+    assert!(started.elapsed() >= HELLO_TIMEOUT);
+    assert_eq!(caps.version, 0);
+    assert!(!caps.supports(FEATURE_SHA256) && !caps.supports(FEATURE_CONSENT));
+    assert_eq!(caps.describe(), "legacy (meta/chunks/done)");
+    // A CLI with none of our features still speaks the protocol, just without extras.
+    let (tx, mut rx) = mpsc::unbounded_channel::<DataMessage>();
+    tx.send(hello(1, &[])).unwrap();
+    let caps = await_hello(&mut rx, HELLO_TIMEOUT).await;
+    assert_eq!(caps.describe(), "v1 []");
+  }
+
+  fn ice(relay_only: bool, no_host_candidates: bool) -> IceArgs {
+    IceArgs {
+      ice_servers: Vec::new(),
+      turn_username: None,
+      turn_credential: None,
+      relay_only,
+      no_relay_only: false,
+      no_host_candidates,
+      host_candidates: false,
+    }
+  }
+
+  #[test]
+  fn mask_related_address_hides_the_lan_address() {
+    let line = "a=candidate:1 1 udp 1686052607 203.0.113.7 50000 typ srflx raddr 192.168.1.20 rport 50000\r\n";
+    assert_eq!(ice(false, false).mask_related_address(line), line);
+    assert_eq!(
+      ice(false, true).mask_related_address(line),
+      "a=candidate:1 1 udp 1686052607 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 0\r\n"
+    );
+  }
+
+  #[test]
+  fn filter_sdp_drops_candidates_the_policy_hides() {
+    let sdp = "v=0\r\n\
+      a=candidate:1 1 udp 2130706431 192.168.1.20 50000 typ host\r\n\
+      a=candidate:2 1 udp 1686052607 203.0.113.7 50000 typ srflx raddr 192.168.1.20 rport 50000\r\n\
+      a=candidate:3 1 udp 41885439 198.51.100.9 3478 typ relay raddr 203.0.113.7 rport 50000\r\n\
+      a=end-of-candidates\r\n";
+    assert_eq!(ice(false, false).filter_sdp(sdp), sdp);
+    assert_eq!(
+      ice(false, true).filter_sdp(sdp),
+      "v=0\r\n\
+      a=candidate:2 1 udp 1686052607 203.0.113.7 50000 typ srflx raddr 0.0.0.0 rport 0\r\n\
+      a=candidate:3 1 udp 41885439 198.51.100.9 3478 typ relay raddr 0.0.0.0 rport 0\r\n\
+      a=end-of-candidates\r\n"
+    );
+    assert_eq!(
+      ice(true, false).filter_sdp(sdp),
+      "v=0\r\n\
+      a=candidate:3 1 udp 41885439 198.51.100.9 3478 typ relay raddr 0.0.0.0 rport 0\r\n\
+      a=end-of-candidates\r\n"
+    );
+  }
+
+  #[test]
+  fn parse_duration_accepts_units() {
+    assert_eq!(parse_duration("45"), Ok(Duration::from_secs(45)));
+    assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("10m"), Ok(Duration::from_secs(600)));
+    assert_eq!(parse_duration(" 2h "), Ok(Duration::from_secs(7200)));
+    assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(86400)));
+  }
+
+  #[test]
+  fn parse_duration_rejects_bad_input() {
+    assert!(parse_duration("").is_err());
+    assert!(parse_duration("m").is_err());
+    assert!(parse_duration("1.5h").is_err());
+    assert!(parse_duration("10M").is_err());
+    assert!(parse_duration("-5s").is_err());
+    assert!(parse_duration("999999999999999999d").is_err());
+  }
+
+  fn stamp(mtime: u64, size: u64) -> (SystemTime, u64) {
+    (SystemTime::UNIX_EPOCH + Duration::from_secs(mtime), size)
+  }
+
+  #[test]
+  fn watch_sends_files_once_they_settle() {
+    let (old, new) = (PathBuf::from("old.txt"), PathBuf::from("new.txt"));
+    let mut state = WatchState {
+      seen: [(old.clone(), stamp(1, 10))].into(),
+      pending: HashMap::new(),
+    };
+    let start = Instant::now();
+    let at = |secs: u64| start + Duration::from_secs(secs);
+    let scan = |files: &[(&PathBuf, (SystemTime, u64))]| {
+      files.iter().map(|(path, stamp)| ((*path).clone(), *stamp)).collect::<HashMap<_, _>>()
+    };
+
+    // Files there from the start are never sent.
+    assert_eq!(state.update(scan(&[(&old, stamp(1, 10))]), at(0)), (vec![], vec![]));
+    // A new file waits until it has stopped changing for WATCH_SETTLE_TIME.
+    assert_eq!(state.update(scan(&[(&old, stamp(1, 10)), (&new, stamp(2, 5))]), at(1)), (vec![], vec![]));
+    assert_eq!(state.update(scan(&[(&old, stamp(1, 10)), (&new, stamp(3, 8))]), at(2)), (vec![], vec![]));
+    assert_eq!(state.update(scan(&[(&old, stamp(1, 10)), (&new, stamp(3, 8))]), at(3)), (vec![], vec![]));
+    assert_eq!(
+      state.update(scan(&[(&old, stamp(1, 10)), (&new, stamp(3, 8))]), at(4)),
+      (vec![], vec![new.clone()])
+    );
+    // Once sent, it is not sent again until it changes.
+    assert_eq!(state.update(scan(&[(&old, stamp(1, 10)), (&new, stamp(3, 8))]), at(9)), (vec![], vec![]));
+    assert_eq!(state.update(scan(&[(&old, stamp(5, 12)), (&new, stamp(3, 8))]), at(10)), (vec![], vec![]));
+    assert_eq!(
+      state.update(scan(&[(&old, stamp(5, 12)), (&new, stamp(3, 8))]), at(12)),
+      (vec![], vec![old.clone()])
+    );
+    assert_eq!(state.update(scan(&[(&old, stamp(5, 12))]), at(13)), (vec![new], vec![]));
+  }
+
+  fn file_info(path: &str, size: u64) -> FileInfo {
+    FileInfo {
+      path: PathBuf::from(path),
+      name: path.to_string(),
+      size,
+      mime: "text/plain".to_string(),
+      inline: None,
+    }
+  }
+
+  #[test]
+  fn shared_feed_replays_the_newest_version_of_each_file() {
+    let mut feed = SharedFeed::default();
+    let (early_tx, mut early_rx) = mpsc::unbounded_channel::<Arc<FileInfo>>();
+    feed.subscribe(early_tx);
+    feed.publish(file_info("a.txt", 1));
+    feed.publish(file_info("b.txt", 1));
+    feed.publish(file_info("a.txt", 2));
+    feed.publish(file_info("c.txt", 1));
+    feed.retract(Path::new("c.txt"));
+    // Peers already connected get every update as it happens.
+    let early: Vec<(String, u64)> = std::iter::from_fn(|| early_rx.try_recv().ok())
+      .map(|info| (info.name.clone(), info.size))
+      .collect();
+    assert_eq!(early.len(), 4);
+    // A peer joining later gets one copy of each file still there.
+    let (late_tx, mut late_rx) = mpsc::unbounded_channel::<Arc<FileInfo>>();
+    feed.subscribe(late_tx);
+    let late: Vec<(String, u64)> = std::iter::from_fn(|| late_rx.try_recv().ok())
+      .map(|info| (info.name.clone(), info.size))
+      .collect();
+    assert_eq!(late, [("b.txt".to_string(), 1), ("a.txt".to_string(), 2)]);
+  }
+
+  /// A scratch directory under the system temp dir, removed again when dropped.
+  pub(crate) struct TempDir(pub(crate) PathBuf);
+
+  impl TempDir {
+    pub(crate) fn new() -> Self {
+      let path = env::temp_dir().join(format!("pairlane-test-{}", Uuid::new_v4()));
+      std::fs::create_dir_all(&path).unwrap();
+      TempDir(path)
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  #[test]
+  fn cli_errors_map_to_their_exit_codes() {
+    let second = Duration::from_secs(1);
+    let cases = [
+      (CliError::WaitTimeout(second), 3),
+      (CliError::ConnectTimeout(second), 4),
+      (CliError::IdleTimeout(second), 5),
+      (CliError::Role("answerer"), 6),
+      (CliError::Key(String::new()), 7),
+      (CliError::Signaling(String::new()), 8),
+      (CliError::RateLimited, 9),
+      (CliError::PeerLost(String::new()), 10),
+      (CliError::Checksum(String::new()), 11),
+      (CliError::Storage(String::new()), 12),
+      (CliError::Refused(String::new()), 13),
+      (CliError::Declined(String::new()), 14),
+    ];
+    for (err, code) in cases {
+      assert_eq!(exit_code(&anyhow::Error::from(err)), code);
+    }
+  }
+
+  #[test]
+  fn exit_code_looks_through_context() {
+    let err = anyhow::Error::from(CliError::Storage("disk full".to_string())).context("write out.bin");
+    assert_eq!(exit_code(&err), 12);
+    assert_eq!(exit_code(&anyhow!("something else")), 1);
+  }
+
+  /// Answers the first connection with `reply`, whatever it asked for.
+  async fn serve_once(reply: &'static str) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+      let (mut stream, _) = listener.accept().await.unwrap();
+      let mut request = Vec::new();
+      let mut buf = [0u8; 1024];
+      while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+          Ok(0) | Err(_) => return,
+          Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+      }
+      let _ = stream.write_all(reply.as_bytes()).await;
+    });
+    addr
+  }
+
+  #[tokio::test]
+  async fn signaling_failures_exit_with_the_signaling_code() {
+    let proxy = serve_once("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 4\r\n\r\nnope").await;
+    let err = create_room(Some("http://rooms.invalid"), Some(&proxy), None, None).await.unwrap_err();
+    assert_eq!(exit_code(&err), 8, "{err:#}");
+    assert!(err.to_string().contains("parse room response"), "{err:#}");
+
+    let proxy = serve_once("HTTP/1.1 403 Forbidden\r\n\r\n").await;
+    let proxy = Url::parse(&format!("http://{proxy}")).unwrap();
+    let err = connect_via_http_proxy(&proxy, "::1", 443).await.unwrap_err();
+    assert_eq!(exit_code(&err), 8, "{err:#}");
+    assert!(err.to_string().contains("[::1]:443"), "{err:#}");
+
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let proxy = Url::parse(&format!("socks5h://{closed}")).unwrap();
+    let err = connect_via_socks5(&proxy, "example.com", 443).await.unwrap_err();
+    assert_eq!(exit_code(&err), 8, "{err:#}");
+  }
+
+  fn cli_with_profile(args: &[&str], profile: &str) -> Cli {
+    let matches = Cli::command().try_get_matches_from(args).unwrap();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap();
+    let mut config: Config = toml::from_str(profile).unwrap();
+    fill_from_profile(&mut cli, &matches, &config.profile.remove("default").unwrap());
+    cli
+  }
+
+  const PROFILE_TOML: &str = r#"
+    [profile.default]
+    output-dir = "/from/profile"
+    ice-servers = ["stun:profile.example:3478"]
+    turn-username = "profile-user"
+    relay-only = true
+    no-host-candidates = true
+    encrypt = false
+  "#;
+
+  #[test]
+  fn profile_fills_what_the_command_line_left_unset() {
+    let cli = cli_with_profile(&["pairlane", "receive", "ROOM"], PROFILE_TOML);
+    let Command::Receive(args) = cli.command else { panic!("not receive") };
+    assert_eq!(args.output_dir, PathBuf::from("/from/profile"));
+    assert_eq!(args.ice.ice_servers, ["stun:profile.example:3478"]);
+    assert_eq!(args.ice.turn_username.as_deref(), Some("profile-user"));
+    assert!(args.ice.relay_only && args.ice.no_host_candidates);
+    let cli = cli_with_profile(&["pairlane", "send", "a.txt"], PROFILE_TOML);
+    let Command::Send(args) = cli.command else { panic!("not send") };
+    assert!(args.no_encrypt);
+  }
+
+  #[test]
+  fn flags_override_the_profile() {
+    let cli = cli_with_profile(
+      &[
+        "pairlane", "receive", "ROOM", "--output-dir", ".", "--ice-server", "stun:flag.example",
+        "--turn-username", "flag-user", "--no-relay-only", "--host-candidates",
+      ],
+      PROFILE_TOML,
+    );
+    let Command::Receive(args) = cli.command else { panic!("not receive") };
+    assert_eq!(args.output_dir, PathBuf::from("."));
+    assert_eq!(args.ice.ice_servers, ["stun:flag.example"]);
+    assert_eq!(args.ice.turn_username.as_deref(), Some("flag-user"));
+    assert!(!args.ice.relay_only && !args.ice.no_host_candidates);
+    let cli = cli_with_profile(&["pairlane", "send", "a.txt", "--encrypt"], PROFILE_TOML);
+    let Command::Send(args) = cli.command else { panic!("not send") };
+    assert!(!args.no_encrypt);
+  }
+
+  #[test]
+  fn profiles_reject_unknown_keys() {
+    assert!(toml::from_str::<Config>("[profile.default]\nrelay_only = true\n").is_err());
+    assert!(toml::from_str::<Config>("[profile.default]\nencrypt = false\n").is_ok());
+  }
+
+  #[test]
+  fn expand_name_template_fills_placeholders() {
+    assert_eq!(
+      expand_name_template("{sender}-{stem}.{ext}", "report.pdf", "bob").unwrap(),
+      "bob-report.pdf"
+    );
+    assert_eq!(expand_name_template("copy of {name}", "a.txt", "").unwrap(), "copy of a.txt");
+    let dated = expand_name_template("{date}_{time}", "a.txt", "").unwrap();
+    assert_eq!(dated.len(), "2024-01-31_235959".len());
+  }
+
+  #[test]
+  fn expand_name_template_keeps_values_inside_the_directory() {
+    assert_eq!(expand_name_template("{sender}-{name}", "a.txt", "../x/y").unwrap(), ".._x_y-a.txt");
+    assert_eq!(expand_name_template("{name}", "a\\b", "").unwrap(), "a_b");
+  }
+
+  #[test]
+  fn expand_name_template_rejects_bad_placeholders() {
+    assert!(expand_name_template("{nope}", "a.txt", "").is_err());
+    assert!(expand_name_template("{name", "a.txt", "").is_err());
+  }
+
+  #[test]
+  fn negotiated_frame_size_never_exceeds_the_peer_limit() {
+    let old = Capabilities::default();
+    let cli = Capabilities {
+      version: 1,
+      features: Vec::new(),
+    };
+    assert_eq!(negotiated_frame_size(Some(0), &cli).unwrap(), MAX_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(Some(1024), &cli).unwrap(), 1024);
+    assert_eq!(negotiated_frame_size(Some(1 << 20), &cli).unwrap(), MAX_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(None, &cli).unwrap(), MAX_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(Some(1024), &old).unwrap(), 1024);
+    assert!(negotiated_frame_size(Some(AES_NONCE_LEN + AES_TAG_LEN), &cli).is_err());
+    assert!(negotiated_frame_size(Some(AES_NONCE_LEN + AES_TAG_LEN), &old).is_err());
+  }
+
+  #[test]
+  fn negotiated_frame_size_keeps_browsers_at_the_default() {
+    let browser = Capabilities::default();
+    // Chrome advertises 256 KiB, Firefox about 1 GiB.
+    assert_eq!(negotiated_frame_size(Some(262_144), &browser).unwrap(), DEFAULT_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(Some(1_073_741_823), &browser).unwrap(), DEFAULT_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(Some(0), &browser).unwrap(), DEFAULT_FRAME_SIZE);
+    assert_eq!(negotiated_frame_size(None, &browser).unwrap(), DEFAULT_FRAME_SIZE);
+  }
+
+  #[test]
+  fn download_quota_counts_in_flight_receivers_but_not_declined_ones() {
+    let mut tally = DownloadTally { limit: Some(2), quota: Some(2), ..DownloadTally::default() };
+    let connected = ["a".to_string(), "b".to_string()];
+    assert!(tally.quota_reached(connected.iter()));
+    tally.declined.insert("b".into());
+    assert!(!tally.quota_reached(connected.iter()));
+    assert!(!tally.finish("a".into(), "10:00:00".into()));
+    assert!(!tally.finish("a".into(), "10:00:05".into()));
+    assert!(!tally.quota_reached(connected.iter()));
+    assert!(tally.finish("c".into(), "10:01:00".into()));
+    assert!(tally.quota_reached(std::iter::empty()));
+    assert_eq!(
+      tally.downloads,
+      vec![("a".to_string(), "10:00:00".to_string()), ("c".to_string(), "10:01:00".to_string())]
+    );
+  }
+
+  #[test]
+  fn downloads_without_a_limit_never_end_the_share() {
+    let mut tally = DownloadTally::default();
+    assert!(!tally.finish("a".into(), "10:00:00".into()));
+    assert!(!tally.quota_reached(["b".to_string()].iter()));
+    assert_eq!(tally.finished.len(), 1);
+  }
+
+  #[test]
+  fn receiver_status_only_counts_a_paired_transfer_as_active() {
+    use ReceiverStatus::*;
+    assert_eq!(Queued(Some(2)).describe(), "waiting in queue (position 2)");
+    assert_eq!(Requeued(None).describe(), "requeued; waiting for the sender to return");
+    for status in [Queued(None), Requeued(Some(1)), SenderGone, Done] {
+      assert_eq!(status.phase(), Phase::Waiting);
+      assert!(!status.is_active(), "{status:?}");
+    }
+    assert_eq!(Connecting.phase(), Phase::Connecting);
+    assert_eq!(Transferring.phase(), Phase::Transferring);
+    assert!(Connecting.is_active() && Transferring.is_active());
+    assert!(SenderGone.is_engaged() && !Done.is_engaged() && !Queued(None).is_engaged());
+  }
+
+  #[test]
+  fn room_limits_are_range_checked_and_exclusive_with_stay_open() {
+    let cli = Cli::try_parse_from(["pairlane", "send", "a.txt", "--max-concurrent", "10", "--max-receivers", "4"]).unwrap();
+    let Command::Send(args) = cli.command else { panic!("not send") };
+    assert_eq!((args.max_concurrent, args.max_receivers), (Some(10), Some(4)));
+    for bad in [
+      &["pairlane", "send", "a.txt", "--max-concurrent", "0"][..],
+      &["pairlane", "send", "a.txt", "--max-concurrent", "11"],
+      &["pairlane", "send", "a.txt", "--max-receivers", "0"],
+      &["pairlane", "send", "a.txt", "--max-receivers", "2", "--stay-open"],
+    ] {
+      assert!(Cli::try_parse_from(bad).is_err(), "{bad:?}");
+    }
+  }
+
+  #[tokio::test]
+  async fn snippets_travel_inline_and_older_metas_are_plain_files() {
+    let info = snippet_file_info("hello\n".to_string()).await.unwrap();
+    assert!(info.name.starts_with("snippet-") && info.name.ends_with(".txt"));
+    assert_eq!((info.size, info.mime.as_str()), (6, "text/plain"));
+    assert_eq!(info.inline.as_deref(), Some(&b"hello\n"[..]));
+    assert!(snippet_file_info(String::new()).await.is_err());
+
+    let meta: DataMessage =
+      serde_json::from_str(r#"{"type":"meta","name":"a.txt","size":1,"mime":"text/plain","encrypted":false}"#).unwrap();
+    assert!(matches!(meta, DataMessage::Meta { snippet: false, .. }));
+  }
+
+  #[test]
+  fn encrypted_stream_chunks_fill_a_frame_and_round_trip() {
+    let crypto = build_crypto(&[7u8; AES_KEY_LEN]).unwrap();
+    let chunk = vec![0xabu8; frame_chunk_size(DEFAULT_FRAME_SIZE, true)];
+    let frame = encrypt_frame(&crypto, &chunk).unwrap();
+    assert_eq!(frame.len(), DEFAULT_FRAME_SIZE);
+    assert_eq!(decrypt_frame(&crypto, &frame).unwrap(), chunk);
+    assert_eq!(frame_chunk_size(DEFAULT_FRAME_SIZE, false), DEFAULT_FRAME_SIZE);
+
+    let other = build_crypto(&[8u8; AES_KEY_LEN]).unwrap();
+    assert!(decrypt_frame(&other, &frame).is_err());
+    assert!(decrypt_frame(&crypto, &frame[..AES_NONCE_LEN - 1]).is_err());
+    assert!(build_crypto(&[0u8; 16]).is_err());
+  }
+
 }
@@ -0,0 +1,215 @@
+//! `--manual-signaling`: the offer and answer travel as copy-pasted blobs instead of through the
+//! signaling server. The offer blob carries the room key.
+
+use anyhow::{anyhow, Context, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::{mpsc, Mutex};
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use crate::consent::prompt_available;
+use crate::events::{emit, log_line, Event};
+use crate::hooks::spawn_exec_hooks;
+use crate::{
+  accept_offer, b64url_decode, b64url_encode, build_crypto, create_offerer_peer, decode_key,
+  generate_key, handle_answer, init_receiver, wait_for_success, Activity, ClientMessage, CliError,
+  FileFeed, FileInfo, IceArgs, Phase, ReceiveArgs, ReceiveProgress, ReceiverState, ReceiverStatus,
+  SendContext, TimeoutArgs,
+};
+
+const MANUAL_PEER_ID: &str = "manual";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManualSignal {
+  sdp: RTCSessionDescription,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  key: Option<String>,
+}
+
+pub(crate) async fn run_send_manual(
+  file_info: FileInfo,
+  no_encrypt: bool,
+  sender_name: Option<String>,
+  ice: IceArgs,
+  timeouts: TimeoutArgs,
+) -> Result<()> {
+  let ice = Arc::new(ice);
+  let files = FileFeed::Single(Arc::new(file_info));
+  let room_key = if no_encrypt { None } else { Some(generate_key()?.to_vec()) };
+  let crypto = match room_key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+
+  // Without a signaling server there is nobody to relay trickled candidates, so they are
+  // dropped here and the offer is only printed once gathering has completed.
+  let (signal_tx, _signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+  let (success_tx, success_rx) = mpsc::unbounded_channel::<String>();
+  let (failure_tx, failure_rx) = mpsc::unbounded_channel::<CliError>();
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let ctx = SendContext {
+    signal_tx,
+    files,
+    crypto,
+    success_tx: Some(success_tx),
+    declined_tx: None,
+    failure_tx: Some(failure_tx),
+    ice: ice.clone(),
+    activity: activity.clone(),
+    sender_name,
+    inbound: None,
+    release_idle: false,
+  };
+  let peer = create_offerer_peer(MANUAL_PEER_ID.to_string(), ctx).await?;
+  let offer = gathered_local_description(&peer.pc, &ice).await?;
+  let sid = peer
+    .state
+    .lock()
+    .await
+    .active_sid
+    .ok_or_else(|| anyhow!("Offer was not created"))?;
+
+  log_line("[manual] offer", "copy the blob below to the receiver");
+  let blob = encode_manual_signal(&ManualSignal {
+    sdp: offer,
+    key: room_key.as_deref().map(b64url_encode),
+  })?;
+  emit(Event::Signal { kind: "offer", blob: &blob });
+  let answer = read_manual_signal("[manual] paste answer").await?;
+  handle_answer(peer.clone(), sid, answer.sdp).await?;
+
+  activity.lock().await.enter(Phase::Connecting);
+  wait_for_success(success_rx, failure_rx, &activity, &timeouts).await?;
+  log_line("[send] completed", "transfer done");
+  let _ = peer.pc.close().await;
+  Ok(())
+}
+
+pub(crate) async fn run_receive_manual(args: ReceiveArgs) -> Result<()> {
+  let ice = Arc::new(args.ice);
+  let offer = read_manual_signal("[manual] paste offer").await?;
+  let key = match args.key.as_deref() {
+    Some(key) => Some(decode_key(key)?),
+    None => offer.key.as_deref().map(decode_key).transpose()?,
+  };
+  let crypto = match key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+
+  let (signal_tx, _signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+  let (success_tx, success_rx) = mpsc::unbounded_channel::<()>();
+  let (failure_tx, failure_rx) = mpsc::unbounded_channel::<CliError>();
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let receiver_state: Arc<Mutex<Option<ReceiverState>>> = Arc::new(Mutex::new(None));
+  let mut progress = ReceiveProgress::new(args.output_dir, crypto, Some(success_tx), activity.clone());
+  progress.failure_tx = Some(failure_tx);
+  progress.screening.confirm = !args.yes && prompt_available();
+  progress.screening.policy = args.policy;
+  progress.saving.output_file = args.output_file;
+  progress.saving.name_template = args.name_template;
+  let hooks = args.exec.map(|command| {
+    let (exec_tx, handle) = spawn_exec_hooks(command, None);
+    progress.reports.exec_tx = Some(exec_tx);
+    handle
+  });
+  let progress = Arc::new(Mutex::new(progress));
+  init_receiver(signal_tx.clone(), receiver_state.clone(), progress.clone(), ice.clone(), None).await?;
+
+  let pc = {
+    let mut guard = receiver_state.lock().await;
+    let state = guard.as_mut().ok_or_else(|| anyhow!("Receiver not initialized"))?;
+    accept_offer(state, MANUAL_PEER_ID.to_string(), 1, offer.sdp, &signal_tx).await?;
+    state.pc.clone()
+  };
+  let answer = gathered_local_description(&pc, &ice).await?;
+  log_line("[manual] answer", "copy the blob below back to the sender");
+  let blob = encode_manual_signal(&ManualSignal { sdp: answer, key: None })?;
+  emit(Event::Signal { kind: "answer", blob: &blob });
+
+  activity.lock().await.enter_receiver(ReceiverStatus::Connecting);
+  let outcome = wait_for_success(success_rx, failure_rx, &activity, &args.timeouts).await;
+  if outcome.is_ok() {
+    log_line("[recv] completed", "transfer done");
+  }
+  // As in `run_receive`, queued hooks finish even after a failure.
+  progress.lock().await.reports.exec_tx = None;
+  if let Some(hooks) = hooks {
+    let _ = hooks.await;
+  }
+  let _ = pc.close().await;
+  outcome
+}
+
+async fn gathered_local_description(pc: &RTCPeerConnection, ice: &IceArgs) -> Result<RTCSessionDescription> {
+  let mut gathered = pc.gathering_complete_promise().await;
+  let _ = gathered.recv().await;
+  let mut local = pc
+    .local_description()
+    .await
+    .ok_or_else(|| anyhow!("Local description is not set"))?;
+  // Non-trickle descriptions carry their candidates inline, so the same policy applies to the SDP.
+  local.sdp = ice.filter_sdp(&local.sdp);
+  Ok(local)
+}
+
+fn encode_manual_signal(signal: &ManualSignal) -> Result<String> {
+  let json = serde_json::to_vec(signal)?;
+  let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+  encoder.write_all(&json)?;
+  Ok(b64url_encode(&encoder.finish()?))
+}
+
+fn decode_manual_signal(blob: &str) -> Result<ManualSignal> {
+  let compressed = b64url_decode(blob.trim()).context("decode signaling blob")?;
+  let mut json = Vec::new();
+  ZlibDecoder::new(compressed.as_slice())
+    .read_to_end(&mut json)
+    .context("inflate signaling blob")?;
+  serde_json::from_slice(&json).context("parse signaling blob")
+}
+
+async fn read_manual_signal(label: &str) -> Result<ManualSignal> {
+  log_line(label, "waiting for a blob on stdin");
+  let mut lines = BufReader::new(tokio::io::stdin()).lines();
+  while let Some(line) = lines.next_line().await? {
+    if line.trim().is_empty() {
+      continue;
+    }
+    return decode_manual_signal(&line);
+  }
+  Err(anyhow!("stdin closed before a signaling blob was pasted"))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
+
+  #[test]
+  fn manual_signal_round_trips() {
+    let sdp = serde_json::from_value(serde_json::json!({ "type": "offer", "sdp": "v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\n" })).unwrap();
+    let blob = encode_manual_signal(&ManualSignal {
+      sdp,
+      key: Some("a2V5".to_string()),
+    })
+    .unwrap();
+    assert!(!blob.contains(['+', '/', '=', '\n']));
+    let signal = decode_manual_signal(&format!("  {blob}\r\n")).unwrap();
+    assert_eq!(signal.sdp.sdp_type, RTCSdpType::Offer);
+    assert_eq!(signal.sdp.sdp, "v=0\r\no=- 1 1 IN IP4 0.0.0.0\r\n");
+    assert_eq!(signal.key.as_deref(), Some("a2V5"));
+  }
+
+  #[test]
+  fn manual_signal_rejects_garbage() {
+    assert!(decode_manual_signal("not a blob!").is_err());
+    assert!(decode_manual_signal(&b64url_encode(b"plain text")).is_err());
+  }
+}
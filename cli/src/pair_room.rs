@@ -0,0 +1,255 @@
+//! Pair rooms: the one-to-one rooms behind `session`, `pipe`, `forward` and `sync`, and the data
+//! channel plumbing those commands share.
+
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, Duration};
+use tokio_tungstenite::tungstenite::Message;
+use url::Url;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::events::{emit, log_line, Event};
+use crate::{
+  accept_offer, connect_signaling, create_room, decode_key, encrypt_frame, generate_key,
+  handle_answer, handle_offer_candidate, handle_receiver_candidate, parse_room_input,
+  spawn_signal_writer, split_signaling, ClientMessage, CliError, IceArgs, OffererPeer,
+  ReceiverState, RoomInput, ServerMessage,
+};
+
+const STREAM_MAX_BUFFERED: usize = 1024 * 1024;
+// Messages queued from a forward/sync channel before the data channel stops reading more.
+const CHANNEL_EVENT_BUFFER: usize = 64;
+
+/// What a pair-room command plugs into `drive_pair_room`; the signaling around it is the same
+/// for all of them.
+pub(crate) trait PairRoomHooks {
+  /// We own the room and `peer_id` joined it: open our side and send the offer.
+  async fn start_offerer(&mut self, room: &PairRoom, peer_id: String) -> Result<Arc<OffererPeer>>;
+
+  /// We joined the peer's room: get ready for the offer it is about to send.
+  async fn start_answerer(&mut self, room: &PairRoom) -> Result<()>;
+
+  /// The peer left. `Some` ends the run with that result, `None` waits for the next peer.
+  async fn peer_gone(&mut self) -> Option<Result<()>>;
+
+  /// Sees each server message before the shared handling does.
+  async fn observe(&mut self, _room: &PairRoom, _msg: &ServerMessage) {}
+
+  /// Resolves once the command has finished on its own, ending the run with its result.
+  async fn finished(&mut self) -> Result<()> {
+    std::future::pending().await
+  }
+
+  /// Runs when the run ends, before the peer connections are closed.
+  async fn finish(&mut self, _room: &PairRoom) {}
+}
+
+/// The signaling state of a pair room that hooks set up their side of the connection with.
+pub(crate) struct PairRoom {
+  pub(crate) signal_tx: mpsc::UnboundedSender<ClientMessage>,
+  pub(crate) receiver_state: Arc<Mutex<Option<ReceiverState>>>,
+  pub(crate) ice: Arc<IceArgs>,
+}
+
+/// Connects to a one-to-one room and relays its signaling until `hooks` end the run.
+pub(crate) async fn drive_pair_room<H: PairRoomHooks>(
+  ws_url: &Url,
+  proxy: Option<&str>,
+  ice: Arc<IceArgs>,
+  hooks: &mut H,
+) -> Result<()> {
+  log_line("[ws] connecting", ws_url.as_ref());
+  let ws_stream = connect_signaling(ws_url, proxy).await?;
+  let (ws_write, mut ws_read) = split_signaling(ws_stream);
+  let (signal_tx, signal_rx) = mpsc::unbounded_channel::<ClientMessage>();
+  let writer = spawn_signal_writer(ws_write, signal_rx);
+  let room = PairRoom {
+    signal_tx,
+    receiver_state: Arc::new(Mutex::new(None)),
+    ice,
+  };
+  let mut peer: Option<Arc<OffererPeer>> = None;
+
+  let result: Result<()> = async {
+    loop {
+      let msg = tokio::select! {
+        msg = ws_read.next() => msg,
+        result = hooks.finished() => return result,
+      };
+      let Some(msg) = msg else {
+        return Err(CliError::Signaling("connection closed".to_string()).into());
+      };
+      let Message::Text(text) = msg.map_err(CliError::websocket)? else { continue };
+      let parsed: ServerMessage = match serde_json::from_str(&text) {
+        Ok(msg) => msg,
+        Err(_) => continue,
+      };
+      hooks.observe(&room, &parsed).await;
+      match parsed {
+        ServerMessage::Role { role, cid } => {
+          emit(Event::Role { role: &role, client_id: &cid });
+        }
+        ServerMessage::Peers { count } => {
+          emit(Event::Peers { count });
+        }
+        ServerMessage::Wait { .. } => {
+          // `wait` after `start` means the room's creator left.
+          if room.receiver_state.lock().await.is_some() {
+            let outcome = hooks.peer_gone().await;
+            if let Some(state) = room.receiver_state.lock().await.take() {
+              let _ = state.pc.close().await;
+            }
+            if let Some(result) = outcome {
+              return result;
+            }
+          }
+        }
+        ServerMessage::Start { peer_id: Some(peer_id) } => {
+          peer = Some(hooks.start_offerer(&room, peer_id).await?);
+        }
+        ServerMessage::Start { peer_id: None } => {
+          hooks.start_answerer(&room).await?;
+        }
+        ServerMessage::Offer { from, sid, sdp } => {
+          let mut guard = room.receiver_state.lock().await;
+          let state = guard.as_mut().ok_or_else(|| anyhow!("Receiver not initialized"))?;
+          accept_offer(state, from, sid, sdp, &room.signal_tx).await?;
+        }
+        ServerMessage::Answer { from: _, sid, sdp } => {
+          if let Some(peer) = peer.clone() {
+            handle_answer(peer, sid, sdp).await?;
+          }
+        }
+        ServerMessage::Candidate { from: _, sid, candidate } => {
+          if let Some(peer) = peer.clone() {
+            handle_offer_candidate(peer, sid, candidate).await?;
+          } else if let Some(state) = room.receiver_state.lock().await.as_mut() {
+            handle_receiver_candidate(state, sid, candidate).await?;
+          }
+        }
+        ServerMessage::PeerLeft { peer_id } => {
+          if let Some(gone) = peer.take_if(|peer| peer.peer_id == peer_id) {
+            let outcome = hooks.peer_gone().await;
+            let _ = gone.pc.close().await;
+            if let Some(result) = outcome {
+              return result;
+            }
+          }
+        }
+        // Only `send --max-downloads` turns receivers away.
+        ServerMessage::Refused { .. } => {}
+      }
+    }
+  }
+  .await;
+
+  hooks.finish(&room).await;
+  if let Some(peer) = peer {
+    let _ = peer.pc.close().await;
+  }
+  if let Some(state) = room.receiver_state.lock().await.take() {
+    let _ = state.pc.close().await;
+  }
+  writer.abort();
+  result
+}
+
+/// Joins `room_input`, or creates a one-to-one room owned by `client_id` when there is none.
+pub(crate) async fn open_pair_room(
+  room_input: Option<&str>,
+  key: Option<&str>,
+  no_encrypt: bool,
+  endpoint: Option<String>,
+  proxy: Option<&str>,
+  client_id: &str,
+) -> Result<RoomInput> {
+  let explicit_key = key.map(decode_key).transpose()?;
+  match room_input {
+    Some(value) => {
+      let parsed = parse_room_input(value)?;
+      Ok(RoomInput {
+        room_id: parsed.room_id,
+        endpoint: endpoint.or(parsed.endpoint),
+        key: explicit_key.or(parsed.key),
+      })
+    }
+    None => {
+      // Strictly one-to-one, so the room never admits a second joiner.
+      let room_id = create_room(endpoint.as_deref(), proxy, Some(client_id), Some(1)).await?;
+      let key = match explicit_key {
+        Some(key) => Some(key),
+        None if no_encrypt => None,
+        None => Some(generate_key()?.to_vec()),
+      };
+      Ok(RoomInput { room_id, endpoint, key })
+    }
+  }
+}
+
+/// Sends one chunk of a raw byte stream (`pipe`, `forward`), holding back while the channel
+/// has a lot queued so a fast reader cannot run ahead of the peer indefinitely.
+pub(crate) async fn send_stream_frame(dc: &RTCDataChannel, crypto: Option<&Aes256Gcm>, chunk: Vec<u8>) -> Result<()> {
+  let payload = match crypto {
+    Some(crypto) => Bytes::from(encrypt_frame(crypto, &chunk)?),
+    None => Bytes::from(chunk),
+  };
+  dc.send(&payload).await?;
+  while dc.buffered_amount().await > STREAM_MAX_BUFFERED {
+    sleep(Duration::from_millis(10)).await;
+  }
+  Ok(())
+}
+
+/// What happened on a data channel, queued so one task can handle it as a sequence instead
+/// of spreading the logic over callbacks.
+pub(crate) enum ChannelEvent {
+  Open,
+  Text(Bytes),
+  Binary(Bytes),
+  Closed,
+}
+
+/// The channel opens before any message can arrive on it.
+pub(crate) async fn wait_channel_open(events: &mut mpsc::Receiver<ChannelEvent>) -> Result<()> {
+  match events.recv().await {
+    Some(ChannelEvent::Open) => Ok(()),
+    _ => Err(anyhow!("Channel closed before it opened")),
+  }
+}
+
+/// The channel's events as a bounded queue. The data channel awaits `on_message`, so a full
+/// queue stops it reading and the peer's sends slow down instead of piling up in memory.
+pub(crate) fn channel_events(dc: &RTCDataChannel) -> mpsc::Receiver<ChannelEvent> {
+  let (event_tx, event_rx) = mpsc::channel::<ChannelEvent>(CHANNEL_EVENT_BUFFER);
+  let message_tx = event_tx.clone();
+  dc.on_message(Box::new(move |msg: DataChannelMessage| {
+    let message_tx = message_tx.clone();
+    Box::pin(async move {
+      let event = if msg.is_string {
+        ChannelEvent::Text(msg.data)
+      } else {
+        ChannelEvent::Binary(msg.data)
+      };
+      let _ = message_tx.send(event).await;
+    })
+  }));
+  let close_tx = event_tx.clone();
+  dc.on_close(Box::new(move || {
+    let close_tx = close_tx.clone();
+    Box::pin(async move {
+      let _ = close_tx.send(ChannelEvent::Closed).await;
+    })
+  }));
+  dc.on_open(Box::new(move || {
+    let event_tx = event_tx.clone();
+    Box::pin(async move {
+      let _ = event_tx.send(ChannelEvent::Open).await;
+    })
+  }));
+  event_rx
+}
@@ -0,0 +1,255 @@
+//! `pairlane pipe`: a netcat-style byte stream between stdin and stdout over a pair room.
+
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Result};
+use std::io::Read;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+use webrtc::data_channel::data_channel_message::DataChannelMessage;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::events::{emit, log_error, Direction, Event, LOG_TO_STDERR};
+use crate::pair_room::{drive_pair_room, open_pair_room, send_stream_frame, PairRoom, PairRoomHooks};
+use crate::{
+  build_crypto, build_room_url_with_key, build_ws_url, decrypt_frame, frame_chunk_size,
+  open_answerer_peer, open_offerer_peer, send_offer, wait_for_drain, Activity, CliError,
+  DataMessage, DEFAULT_FRAME_SIZE, OffererPeer, PipeArgs, RoomInput,
+};
+
+const PIPE_CHANNEL_LABEL: &str = "pipe";
+const PIPE_READ_AHEAD: usize = 16;
+
+#[derive(Debug)]
+enum PipeEvent {
+  /// Everything read from stdin has been sent, followed by `eof`.
+  LocalEof,
+  /// The peer sent `eof`; stdout has been flushed and closed.
+  RemoteEof,
+  Failed(anyhow::Error),
+}
+
+pub(crate) async fn run_pipe(args: PipeArgs) -> Result<()> {
+  LOG_TO_STDERR.store(true, Ordering::Relaxed);
+  let proxy = args.proxy.as_deref();
+  let ice = Arc::new(args.ice);
+  let client_id = Uuid::new_v4().to_string();
+  let RoomInput { room_id, endpoint: endpoint_override, key: room_key } = open_pair_room(
+    args.room_input.as_deref(),
+    args.key.as_deref(),
+    args.no_encrypt,
+    args.endpoint,
+    proxy,
+    &client_id,
+  )
+  .await?;
+  let crypto = match room_key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+  let ws_url = build_ws_url(endpoint_override.as_deref(), &room_id, &client_id)?;
+
+  let room_url = build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
+  });
+
+  // Read ahead on a plain thread (see `run_session`), bounded so a fast producer waits for the peer.
+  let chunk_size = frame_chunk_size(DEFAULT_FRAME_SIZE, crypto.is_some());
+  let (chunk_tx, chunk_rx) = mpsc::channel::<Vec<u8>>(PIPE_READ_AHEAD);
+  std::thread::spawn(move || {
+    let mut stdin = std::io::stdin().lock();
+    loop {
+      let mut buffer = vec![0u8; chunk_size];
+      let read = match stdin.read(&mut buffer) {
+        Ok(0) => break,
+        Ok(read) => read,
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+        Err(err) => {
+          log_error("pipe", &format!("stdin: {err}"));
+          break;
+        }
+      };
+      buffer.truncate(read);
+      if chunk_tx.blocking_send(buffer).is_err() {
+        break;
+      }
+    }
+  });
+  let chunk_rx = Arc::new(Mutex::new(Some(chunk_rx)));
+  let (event_tx, event_rx) = mpsc::unbounded_channel::<PipeEvent>();
+
+  let mut hooks = PipeHooks {
+    crypto,
+    chunk_rx,
+    event_tx,
+    event_rx,
+    local_eof: false,
+    remote_eof: false,
+  };
+  drive_pair_room(&ws_url, proxy, ice, &mut hooks).await
+}
+
+/// `pipe` in a pair room: stdin goes out over one channel and the peer's stream to stdout.
+struct PipeHooks {
+  crypto: Option<Arc<Aes256Gcm>>,
+  chunk_rx: Arc<Mutex<Option<mpsc::Receiver<Vec<u8>>>>>,
+  event_tx: mpsc::UnboundedSender<PipeEvent>,
+  event_rx: mpsc::UnboundedReceiver<PipeEvent>,
+  local_eof: bool,
+  remote_eof: bool,
+}
+
+impl PairRoomHooks for PipeHooks {
+  async fn start_offerer(&mut self, room: &PairRoom, peer_id: String) -> Result<Arc<OffererPeer>> {
+    emit(Event::PeerJoined { peer_id: &peer_id });
+    let (offerer, dc) =
+      open_offerer_peer(peer_id, PIPE_CHANNEL_LABEL, room.signal_tx.clone(), room.ice.clone()).await?;
+    wire_pipe_channel(dc, self.crypto.clone(), self.chunk_rx.clone(), self.event_tx.clone());
+    send_offer(offerer.clone(), room.signal_tx.clone()).await?;
+    Ok(offerer)
+  }
+
+  async fn start_answerer(&mut self, room: &PairRoom) -> Result<()> {
+    let activity = Arc::new(Mutex::new(Activity::new()));
+    let pc = open_answerer_peer(room.signal_tx.clone(), room.receiver_state.clone(), activity, room.ice.clone()).await?;
+    let crypto = self.crypto.clone();
+    let chunk_rx = self.chunk_rx.clone();
+    let event_tx = self.event_tx.clone();
+    pc.on_data_channel(Box::new(move |dc| {
+      if dc.label() == PIPE_CHANNEL_LABEL {
+        wire_pipe_channel(dc, crypto.clone(), chunk_rx.clone(), event_tx.clone());
+      } else {
+        let _ = event_tx.send(PipeEvent::Failed(anyhow!(
+          "Peer opened a '{}' channel; is it running `pairlane pipe`?",
+          dc.label()
+        )));
+      }
+      Box::pin(async {})
+    }));
+    Ok(())
+  }
+
+  async fn peer_gone(&mut self) -> Option<Result<()>> {
+    // Once the peer's stream has ended nothing more is owed to us; whatever we still had to
+    // send has no reader left, which is what a closed pipe means anyway.
+    if self.remote_eof {
+      emit(Event::PeerLeft);
+      Some(Ok(()))
+    } else {
+      Some(Err(CliError::PeerLost("Peer left before the end of its stream".to_string()).into()))
+    }
+  }
+
+  async fn finished(&mut self) -> Result<()> {
+    loop {
+      // The hooks hold a sender themselves, so the channel never closes.
+      let Some(event) = self.event_rx.recv().await else {
+        return std::future::pending().await;
+      };
+      match event {
+        PipeEvent::LocalEof => {
+          emit(Event::Eof { direction: Direction::Send });
+          self.local_eof = true;
+        }
+        PipeEvent::RemoteEof => {
+          emit(Event::Eof { direction: Direction::Receive });
+          self.remote_eof = true;
+        }
+        PipeEvent::Failed(err) => return Err(err),
+      }
+      if self.local_eof && self.remote_eof {
+        return Ok(());
+      }
+    }
+  }
+}
+
+fn wire_pipe_channel(
+  dc: Arc<RTCDataChannel>,
+  crypto: Option<Arc<Aes256Gcm>>,
+  chunk_rx: Arc<Mutex<Option<mpsc::Receiver<Vec<u8>>>>>,
+  event_tx: mpsc::UnboundedSender<PipeEvent>,
+) {
+  let stdout = Arc::new(Mutex::new(Some(tokio::io::stdout())));
+  let message_crypto = crypto.clone();
+  let message_tx = event_tx.clone();
+  dc.on_message(Box::new(move |msg: DataChannelMessage| {
+    let stdout = stdout.clone();
+    let crypto = message_crypto.clone();
+    let event_tx = message_tx.clone();
+    Box::pin(async move {
+      let mut guard = stdout.lock().await;
+      let Some(out) = guard.as_mut() else { return };
+      if msg.is_string {
+        if let Ok(DataMessage::Eof) = serde_json::from_slice::<DataMessage>(&msg.data) {
+          let _ = out.flush().await;
+          guard.take();
+          close_stdout();
+          let _ = event_tx.send(PipeEvent::RemoteEof);
+        }
+        return;
+      }
+      let written = match crypto.as_ref() {
+        Some(crypto) => match decrypt_frame(crypto, &msg.data) {
+          Ok(plain) => out.write_all(&plain).await,
+          Err(err) => {
+            guard.take();
+            let _ = event_tx.send(PipeEvent::Failed(err.context("Decrypt failed (wrong key?)")));
+            return;
+          }
+        },
+        None => out.write_all(&msg.data).await,
+      };
+      // Flushed per frame: a pipe is often interactive, so bytes must not sit in a buffer.
+      if let Err(err) = async { written?; out.flush().await }.await {
+        guard.take();
+        let _ = event_tx.send(PipeEvent::Failed(anyhow!(err).context("write stdout")));
+      }
+    })
+  }));
+
+  let open_dc = dc.clone();
+  dc.on_open(Box::new(move || {
+    Box::pin(async move {
+      tokio::spawn(async move {
+        let Some(chunk_rx) = chunk_rx.lock().await.take() else { return };
+        let event = match pump_stdin(&open_dc, chunk_rx, crypto).await {
+          Ok(()) => PipeEvent::LocalEof,
+          Err(err) => PipeEvent::Failed(err),
+        };
+        let _ = event_tx.send(event);
+      });
+    })
+  }));
+}
+
+async fn pump_stdin(
+  dc: &RTCDataChannel,
+  mut chunk_rx: mpsc::Receiver<Vec<u8>>,
+  crypto: Option<Arc<Aes256Gcm>>,
+) -> Result<()> {
+  while let Some(chunk) = chunk_rx.recv().await {
+    send_stream_frame(dc, crypto.as_deref(), chunk).await?;
+  }
+  dc.send_text(serde_json::to_string(&DataMessage::Eof)?).await?;
+  wait_for_drain(dc).await;
+  Ok(())
+}
+
+/// Closes our end of stdout so the process reading it sees end-of-file while the other
+/// direction is still open (the half-close `nc -N` gives you). fd 1 is pointed at /dev/null
+/// rather than closed, so a socket or file opened later can never take its place.
+fn close_stdout() {
+  #[cfg(unix)]
+  {
+    use std::os::fd::AsRawFd;
+    if let Ok(null) = std::fs::OpenOptions::new().write(true).open("/dev/null") {
+      // SAFETY: both descriptors are open; dup2 atomically replaces fd 1 and leaves `null` as is.
+      unsafe { libc::dup2(null.as_raw_fd(), 1) };
+    }
+  }
+}
@@ -0,0 +1,108 @@
+//! The room URL as a QR code, in the terminal or as an image file.
+
+use anyhow::{anyhow, Context, Result};
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::events::{log_line, JSON_EVENTS};
+
+const QR_QUIET_ZONE: usize = 4;
+const QR_PNG_MODULE_PIXELS: usize = 8;
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum QrImageFormat {
+  Png,
+  Svg,
+}
+
+impl QrImageFormat {
+  pub(crate) fn from_path(path: &Path) -> Result<Self> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+      Some("png") => Ok(QrImageFormat::Png),
+      Some("svg") => Ok(QrImageFormat::Svg),
+      _ => Err(anyhow!("--qr-file must end in .png or .svg: {}", path.display())),
+    }
+  }
+}
+
+/// Shows the room URL as a QR code so a phone can join without typing the `#k=` key.
+pub(crate) fn show_room_qr(url: &str, print: bool, file: Option<&Path>) -> Result<()> {
+  if !print && file.is_none() {
+    return Ok(());
+  }
+  let code = QrCode::new(url).context("encode room URL as QR code")?;
+  if print {
+    // Light modules are the drawn ones, as in `qrencode -t UTF8`, so the code scans on the
+    // usual dark terminal background.
+    let text = code
+      .render::<unicode::Dense1x2>()
+      .dark_color(unicode::Dense1x2::Light)
+      .light_color(unicode::Dense1x2::Dark)
+      .build();
+    if JSON_EVENTS.load(Ordering::Relaxed) {
+      eprintln!("{text}");
+    } else {
+      println!("{text}");
+    }
+  }
+  if let Some(path) = file {
+    let image = match QrImageFormat::from_path(path)? {
+      QrImageFormat::Svg => code.render::<svg::Color>().min_dimensions(256, 256).build().into_bytes(),
+      QrImageFormat::Png => encode_qr_png(&code)?,
+    };
+    std::fs::write(path, image).with_context(|| format!("write {}", path.display()))?;
+    log_line("[room] qr", &path.display().to_string());
+  }
+  Ok(())
+}
+
+fn encode_qr_png(code: &QrCode) -> Result<Vec<u8>> {
+  let modules = code.width();
+  let side = (modules + 2 * QR_QUIET_ZONE) * QR_PNG_MODULE_PIXELS;
+  let mut pixels = vec![u8::MAX; side * side];
+  for (index, color) in code.to_colors().into_iter().enumerate() {
+    if color != qrcode::Color::Dark {
+      continue;
+    }
+    let left = (index % modules + QR_QUIET_ZONE) * QR_PNG_MODULE_PIXELS;
+    let top = (index / modules + QR_QUIET_ZONE) * QR_PNG_MODULE_PIXELS;
+    for row in top..top + QR_PNG_MODULE_PIXELS {
+      pixels[row * side + left..row * side + left + QR_PNG_MODULE_PIXELS].fill(0);
+    }
+  }
+  let mut png_bytes = Vec::new();
+  let mut encoder = png::Encoder::new(&mut png_bytes, side as u32, side as u32);
+  encoder.set_color(png::ColorType::Grayscale);
+  encoder.set_depth(png::BitDepth::Eight);
+  let mut writer = encoder.write_header()?;
+  writer.write_image_data(&pixels)?;
+  writer.finish()?;
+  Ok(png_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn qr_files_are_picked_by_extension_and_png_keeps_a_quiet_zone() {
+    assert!(matches!(QrImageFormat::from_path(Path::new("room.PNG")).unwrap(), QrImageFormat::Png));
+    assert!(matches!(QrImageFormat::from_path(Path::new("room.svg")).unwrap(), QrImageFormat::Svg));
+    assert!(QrImageFormat::from_path(Path::new("room.jpg")).is_err());
+    assert!(QrImageFormat::from_path(Path::new("room")).is_err());
+
+    let code = QrCode::new("https://example.com/r/abc#k=key").unwrap();
+    let png_bytes = encode_qr_png(&code).unwrap();
+    let mut reader = png::Decoder::new(png_bytes.as_slice()).read_info().unwrap();
+    let mut pixels = vec![0u8; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels).unwrap();
+    let side = (code.width() + 2 * QR_QUIET_ZONE) * QR_PNG_MODULE_PIXELS;
+    assert_eq!((frame.width as usize, frame.height as usize), (side, side));
+    let margin = QR_QUIET_ZONE * QR_PNG_MODULE_PIXELS;
+    assert!(pixels[..margin * side].iter().all(|&pixel| pixel == u8::MAX));
+    assert_eq!(pixels[margin * side + margin], 0, "finder pattern corner is dark");
+  }
+}
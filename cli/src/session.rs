@@ -0,0 +1,157 @@
+//! `pairlane session`: both peers send and receive files over one connection.
+
+use aes_gcm::Aes256Gcm;
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+
+use crate::events::{emit, log_error, log_line, Event};
+use crate::pair_room::{drive_pair_room, open_pair_room, PairRoom, PairRoomHooks};
+use crate::{
+  build_crypto, build_room_url_with_key, build_ws_url, create_offerer_peer, init_receiver,
+  load_file_info, reset_receiver, Activity, FileFeed, OffererPeer, ReceiveProgress, ReceiverStatus,
+  RoomInput, SendContext, ServerMessage, SessionArgs, SharedFeed,
+};
+
+pub(crate) async fn run_session(args: SessionArgs) -> Result<()> {
+  let proxy = args.proxy.as_deref();
+  let ice = Arc::new(args.ice);
+  let client_id = Uuid::new_v4().to_string();
+  let RoomInput { room_id, endpoint: endpoint_override, key: room_key } = open_pair_room(
+    args.room_input.as_deref(),
+    args.key.as_deref(),
+    args.no_encrypt,
+    args.endpoint,
+    proxy,
+    &client_id,
+  )
+  .await?;
+  let crypto = match room_key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+  let ws_url = build_ws_url(endpoint_override.as_deref(), &room_id, &client_id)?;
+
+  let room_url = build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
+  });
+
+  let feed = Arc::new(Mutex::new(SharedFeed::default()));
+  for path in &args.files {
+    feed.lock().await.publish(load_file_info(path).await?);
+  }
+  // A plain thread rather than tokio's stdin, whose blocking read would keep the runtime
+  // from shutting down when the session ends.
+  let (line_tx, mut line_rx) = mpsc::unbounded_channel::<String>();
+  std::thread::spawn(move || {
+    for line in std::io::stdin().lines().map_while(Result::ok) {
+      if line_tx.send(line).is_err() {
+        break;
+      }
+    }
+  });
+  let stdin_feed = feed.clone();
+  tokio::spawn(async move {
+    while let Some(line) = line_rx.recv().await {
+      let path = line.trim();
+      if path.is_empty() {
+        continue;
+      }
+      match load_file_info(Path::new(path)).await {
+        Ok(info) => {
+          log_line("[session] queued", &info.name);
+          stdin_feed.lock().await.publish(info);
+        }
+        Err(err) => log_error("session", &format!("{path}: {err:#}")),
+      }
+    }
+  });
+
+  let activity = Arc::new(Mutex::new(Activity::new()));
+  let progress = Arc::new(Mutex::new(ReceiveProgress::new(
+    args.output_dir.clone(),
+    crypto.clone(),
+    None,
+    activity.clone(),
+  )));
+  let mut hooks = SessionHooks {
+    feed,
+    crypto,
+    activity,
+    progress,
+    sender_name: args.sender_name,
+  };
+  drive_pair_room(&ws_url, proxy, ice, &mut hooks).await
+}
+
+/// `session` in a pair room. Whichever role the server hands out, both sides send and receive
+/// over the same channel.
+struct SessionHooks {
+  feed: Arc<Mutex<SharedFeed>>,
+  crypto: Option<Arc<Aes256Gcm>>,
+  activity: Arc<Mutex<Activity>>,
+  progress: Arc<Mutex<ReceiveProgress>>,
+  sender_name: Option<String>,
+}
+
+impl SessionHooks {
+  fn send_context(&self, room: &PairRoom) -> SendContext {
+    SendContext {
+      signal_tx: room.signal_tx.clone(),
+      files: FileFeed::Shared(self.feed.clone()),
+      crypto: self.crypto.clone(),
+      success_tx: None,
+      declined_tx: None,
+      failure_tx: None,
+      ice: room.ice.clone(),
+      activity: self.activity.clone(),
+      sender_name: self.sender_name.clone(),
+      inbound: Some(self.progress.clone()),
+      release_idle: false,
+    }
+  }
+}
+
+impl PairRoomHooks for SessionHooks {
+  async fn start_offerer(&mut self, room: &PairRoom, peer_id: String) -> Result<Arc<OffererPeer>> {
+    log_line("[session] peer", &peer_id);
+    create_offerer_peer(peer_id, self.send_context(room)).await
+  }
+
+  async fn start_answerer(&mut self, room: &PairRoom) -> Result<()> {
+    self.activity.lock().await.enter_receiver(ReceiverStatus::Connecting);
+    init_receiver(
+      room.signal_tx.clone(),
+      room.receiver_state.clone(),
+      self.progress.clone(),
+      room.ice.clone(),
+      Some(self.send_context(room)),
+    )
+    .await
+  }
+
+  async fn peer_gone(&mut self) -> Option<Result<()>> {
+    log_line("[session] ended", "peer left");
+    Some(Ok(()))
+  }
+
+  async fn observe(&mut self, room: &PairRoom, msg: &ServerMessage) {
+    match msg {
+      ServerMessage::Wait { position } if room.receiver_state.lock().await.is_none() => {
+        self.activity.lock().await.enter_receiver(ReceiverStatus::Queued(*position));
+      }
+      ServerMessage::Offer { from, .. } => {
+        self.progress.lock().await.sender = Some(from.clone());
+      }
+      _ => {}
+    }
+  }
+
+  async fn finish(&mut self, room: &PairRoom) {
+    reset_receiver(&room.receiver_state, &self.progress).await;
+  }
+}
@@ -0,0 +1,732 @@
+//! `pairlane sync`: rsync-style delta sync of a directory over a pair room.
+
+use aes_gcm::Aes256Gcm;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Duration;
+use uuid::Uuid;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::events::{emit, log_line, Event};
+use crate::pair_room::{
+  channel_events, drive_pair_room, open_pair_room, send_stream_frame, wait_channel_open,
+  ChannelEvent, PairRoom, PairRoomHooks,
+};
+use crate::{
+  build_crypto, build_room_url_with_key, build_ws_url, decrypt_frame, frame_chunk_size, hex_digest,
+  open_answerer_peer, open_offerer_peer, send_offer, wait_for_drain, Activity, CliError,
+  DEFAULT_FRAME_SIZE, OffererPeer, RoomInput, SyncArgs,
+};
+
+const SYNC_CHANNEL_LABEL: &str = "sync";
+const SYNC_BLOCK_SIZE: u64 = 1024 * 1024;
+// Entries per catalog/want/hashes message, keeping each well under the SCTP message limit.
+const SYNC_LIST_BATCH: usize = 128;
+
+/// `pairlane sync` control messages. Unlike `DataMessage` they flow both ways: the receiver
+/// describes what it has and the sender asks for what it needs before sending blocks.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum SyncMessage {
+  #[serde(rename = "sync-catalog")]
+  Catalog { files: Vec<SyncEntry>, last: bool },
+  #[serde(rename = "sync-want")]
+  Want { paths: Vec<String>, last: bool },
+  #[serde(rename = "sync-hashes")]
+  Hashes { path: String, first: u64, hashes: Vec<String> },
+  #[serde(rename = "sync-ready")]
+  Ready,
+  #[serde(rename = "sync-file")]
+  File { path: String, size: u64, mtime: u64 },
+  #[serde(rename = "sync-block")]
+  Block { index: u64, sha256: String },
+  #[serde(rename = "sync-file-end")]
+  FileEnd,
+  #[serde(rename = "sync-done")]
+  Done,
+  #[serde(rename = "sync-complete")]
+  Complete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SyncEntry {
+  path: String,
+  size: u64,
+  /// Whole seconds since the Unix epoch.
+  mtime: u64,
+}
+
+#[derive(Clone)]
+enum SyncMode {
+  Send(PathBuf),
+  Receive(PathBuf),
+}
+
+/// A file being patched in place by `sync --into`, and the block currently arriving.
+struct SyncWrite {
+  file: File,
+  path: String,
+  size: u64,
+  mtime: u64,
+  block: Option<SyncBlock>,
+}
+
+struct SyncBlock {
+  index: u64,
+  sha256: String,
+  hasher: Sha256,
+  /// Bytes still due before the block reaches its length, as set by the file's size.
+  remaining: u64,
+}
+
+pub(crate) async fn run_sync(args: SyncArgs) -> Result<()> {
+  let mut room_input = args.room_input;
+  let mut source = args.source;
+  // With --into, clap parses a lone ROOM positional as SRC, so move it back.
+  if args.into.is_some() && room_input.is_none() {
+    room_input = source.take().map(|value| value.to_string_lossy().into_owned());
+  }
+  let mode = match (source, args.into) {
+    (Some(_), Some(_)) => return Err(anyhow!("SRC cannot be combined with --into")),
+    (Some(source), None) => {
+      if !tokio::fs::metadata(&source).await.with_context(|| format!("read {}", source.display()))?.is_dir() {
+        return Err(anyhow!("{} is not a directory", source.display()));
+      }
+      SyncMode::Send(source)
+    }
+    (None, Some(into)) => SyncMode::Receive(into),
+    (None, None) => return Err(anyhow!("Directory is required (usage: sync <SRC> or sync --into <DST> <ROOM>)")),
+  };
+
+  let proxy = args.proxy.as_deref();
+  let ice = Arc::new(args.ice);
+  let client_id = Uuid::new_v4().to_string();
+  let RoomInput { room_id, endpoint: endpoint_override, key: room_key } = open_pair_room(
+    room_input.as_deref(),
+    args.key.as_deref(),
+    args.no_encrypt,
+    args.endpoint,
+    proxy,
+    &client_id,
+  )
+  .await?;
+  let crypto = match room_key.as_deref() {
+    Some(key) => Some(Arc::new(build_crypto(key)?)),
+    None => None,
+  };
+  let ws_url = build_ws_url(endpoint_override.as_deref(), &room_id, &client_id)?;
+
+  let room_url = build_room_url_with_key(endpoint_override.as_deref(), &room_id, room_key.as_deref())?;
+  emit(Event::Room {
+    room_id: &room_id,
+    url: Some(&room_url),
+  });
+
+  let (done_tx, done_rx) = mpsc::unbounded_channel::<Result<()>>();
+  let mut hooks = SyncHooks {
+    mode,
+    crypto,
+    done_tx,
+    done_rx,
+  };
+  drive_pair_room(&ws_url, proxy, ice, &mut hooks).await
+}
+
+/// `sync` in a pair room: one channel, and the run ends when the sync task reports.
+struct SyncHooks {
+  mode: SyncMode,
+  crypto: Option<Arc<Aes256Gcm>>,
+  done_tx: mpsc::UnboundedSender<Result<()>>,
+  done_rx: mpsc::UnboundedReceiver<Result<()>>,
+}
+
+impl PairRoomHooks for SyncHooks {
+  async fn start_offerer(&mut self, room: &PairRoom, peer_id: String) -> Result<Arc<OffererPeer>> {
+    log_line("[sync] peer", &peer_id);
+    let (offerer, dc) =
+      open_offerer_peer(peer_id, SYNC_CHANNEL_LABEL, room.signal_tx.clone(), room.ice.clone()).await?;
+    start_sync(dc, self.mode.clone(), self.crypto.clone(), self.done_tx.clone());
+    send_offer(offerer.clone(), room.signal_tx.clone()).await?;
+    Ok(offerer)
+  }
+
+  async fn start_answerer(&mut self, room: &PairRoom) -> Result<()> {
+    let activity = Arc::new(Mutex::new(Activity::new()));
+    let pc = open_answerer_peer(room.signal_tx.clone(), room.receiver_state.clone(), activity, room.ice.clone()).await?;
+    let mode = self.mode.clone();
+    let crypto = self.crypto.clone();
+    let done_tx = self.done_tx.clone();
+    pc.on_data_channel(Box::new(move |dc| {
+      if dc.label() == SYNC_CHANNEL_LABEL {
+        start_sync(dc, mode.clone(), crypto.clone(), done_tx.clone());
+      } else {
+        let _ = done_tx.send(Err(anyhow!(
+          "Peer opened a '{}' channel; is it running `pairlane sync`?",
+          dc.label()
+        )));
+      }
+      Box::pin(async {})
+    }));
+    Ok(())
+  }
+
+  /// The sender leaves as soon as it reads `sync-complete`, which can reach us through
+  /// signaling before our own side has finished, so give the sync task a moment to report first.
+  async fn peer_gone(&mut self) -> Option<Result<()>> {
+    Some(match tokio::time::timeout(Duration::from_secs(2), self.done_rx.recv()).await {
+      Ok(Some(result)) => result,
+      _ => Err(CliError::PeerLost("Peer left before the sync finished".to_string()).into()),
+    })
+  }
+
+  async fn finished(&mut self) -> Result<()> {
+    // The hooks hold a sender themselves, so the channel never closes.
+    match self.done_rx.recv().await {
+      Some(result) => result,
+      None => std::future::pending().await,
+    }
+  }
+}
+
+fn start_sync(
+  dc: Arc<RTCDataChannel>,
+  mode: SyncMode,
+  crypto: Option<Arc<Aes256Gcm>>,
+  done_tx: mpsc::UnboundedSender<Result<()>>,
+) {
+  let mut events = channel_events(&dc);
+  tokio::spawn(async move {
+    let result = match mode {
+      SyncMode::Send(root) => sync_send(&dc, &mut events, &root, crypto.as_deref()).await,
+      SyncMode::Receive(root) => sync_receive(&dc, &mut events, &root, crypto.as_deref()).await,
+    };
+    let _ = done_tx.send(result);
+  });
+}
+
+async fn sync_send(
+  dc: &RTCDataChannel,
+  events: &mut mpsc::Receiver<ChannelEvent>,
+  root: &Path,
+  crypto: Option<&Aes256Gcm>,
+) -> Result<()> {
+  wait_channel_open(events).await?;
+  let local = walk_sync_dir(root).await?;
+  let total: u64 = local.iter().map(|(entry, _)| entry.size).sum();
+  log_line("[sync] source", &format!("{} files, {total} bytes", local.len()));
+
+  let mut remote: HashMap<String, SyncEntry> = HashMap::new();
+  loop {
+    match next_sync_message(events).await? {
+      SyncMessage::Catalog { files, last } => {
+        remote.extend(files.into_iter().map(|entry| (entry.path.clone(), entry)));
+        if last {
+          break;
+        }
+      }
+      other => return Err(anyhow!("Unexpected {other:?} while waiting for the catalog")),
+    }
+  }
+  log_line("[sync] peer has", &format!("{} files", remote.len()));
+
+  let changed = changed_sync_entries(&local, &remote);
+  let want = sync_hashes_wanted(&changed, &remote);
+  let mut batches = want.chunks(SYNC_LIST_BATCH).peekable();
+  if batches.peek().is_none() {
+    send_sync(dc, &SyncMessage::Want { paths: Vec::new(), last: true }).await?;
+  }
+  while let Some(batch) = batches.next() {
+    let last = batches.peek().is_none();
+    send_sync(dc, &SyncMessage::Want { paths: batch.to_vec(), last }).await?;
+  }
+
+  let mut remote_hashes: HashMap<String, Vec<String>> = HashMap::new();
+  loop {
+    match next_sync_message(events).await? {
+      SyncMessage::Hashes { path, first, hashes } => {
+        let known = remote_hashes.entry(path).or_default();
+        known.truncate(first as usize);
+        known.extend(hashes);
+      }
+      SyncMessage::Ready => break,
+      other => return Err(anyhow!("Unexpected {other:?} while waiting for block hashes")),
+    }
+  }
+
+  let changed_paths: HashSet<&str> = changed.iter().map(|entry| entry.path.as_str()).collect();
+  let mut sent = 0u64;
+  let mut block = Vec::with_capacity(SYNC_BLOCK_SIZE as usize);
+  for (entry, path) in &local {
+    if !changed_paths.contains(entry.path.as_str()) {
+      continue;
+    }
+    send_sync(
+      dc,
+      &SyncMessage::File {
+        path: entry.path.clone(),
+        size: entry.size,
+        mtime: entry.mtime,
+      },
+    )
+    .await?;
+    let theirs = remote_hashes.get(&entry.path).map(Vec::as_slice).unwrap_or_default();
+    let ours = hash_sync_blocks(path).await?;
+    let differing = differing_blocks(&ours, theirs);
+    let mut file = File::open(path).await.with_context(|| format!("open {}", path.display()))?;
+    for &index in &differing {
+      file.seek(std::io::SeekFrom::Start(index as u64 * SYNC_BLOCK_SIZE)).await?;
+      block.clear();
+      (&mut file).take(SYNC_BLOCK_SIZE).read_to_end(&mut block).await?;
+      // Hashed again as read, so a file changing under us fails the receiver's check cleanly.
+      let sha256 = hash_sync_block(&block);
+      send_sync(dc, &SyncMessage::Block { index: index as u64, sha256 }).await?;
+      for chunk in block.chunks(frame_chunk_size(DEFAULT_FRAME_SIZE, crypto.is_some())) {
+        send_stream_frame(dc, crypto, chunk.to_vec()).await?;
+      }
+      sent += block.len() as u64;
+    }
+    send_sync(dc, &SyncMessage::FileEnd).await?;
+    log_line(
+      "[sync] sent",
+      &format!("{} ({}/{} blocks)", entry.path, differing.len(), ours.len()),
+    );
+  }
+  send_sync(dc, &SyncMessage::Done).await?;
+
+  match next_sync_message(events).await? {
+    SyncMessage::Complete => {}
+    other => return Err(anyhow!("Unexpected {other:?} while waiting for the receiver to finish")),
+  }
+  log_line(
+    "[sync] done",
+    &format!(
+      "{} updated, {} unchanged, {sent} of {total} bytes sent",
+      changed.len(),
+      local.len() - changed.len()
+    ),
+  );
+  Ok(())
+}
+
+async fn sync_receive(
+  dc: &RTCDataChannel,
+  events: &mut mpsc::Receiver<ChannelEvent>,
+  root: &Path,
+  crypto: Option<&Aes256Gcm>,
+) -> Result<()> {
+  tokio::fs::create_dir_all(root)
+    .await
+    .with_context(|| format!("create {}", root.display()))?;
+  wait_channel_open(events).await?;
+  let local = walk_sync_dir(root).await?;
+  log_line("[sync] have", &format!("{} files", local.len()));
+  let entries: Vec<SyncEntry> = local.iter().map(|(entry, _)| entry.clone()).collect();
+  let mut batches = entries.chunks(SYNC_LIST_BATCH).peekable();
+  if batches.peek().is_none() {
+    send_sync(dc, &SyncMessage::Catalog { files: Vec::new(), last: true }).await?;
+  }
+  while let Some(batch) = batches.next() {
+    let last = batches.peek().is_none();
+    send_sync(dc, &SyncMessage::Catalog { files: batch.to_vec(), last }).await?;
+  }
+
+  let paths: HashMap<&str, &PathBuf> = local.iter().map(|(entry, path)| (entry.path.as_str(), path)).collect();
+  let mut want = Vec::new();
+  loop {
+    match next_sync_message(events).await? {
+      SyncMessage::Want { paths, last } => {
+        want.extend(paths);
+        if last {
+          break;
+        }
+      }
+      other => return Err(anyhow!("Unexpected {other:?} while waiting for the sender")),
+    }
+  }
+  for relative in &want {
+    let Some(path) = paths.get(relative.as_str()) else { continue };
+    let hashes = hash_sync_blocks(path).await?;
+    for (batch, hashes) in hashes.chunks(SYNC_LIST_BATCH).enumerate() {
+      send_sync(
+        dc,
+        &SyncMessage::Hashes {
+          path: relative.clone(),
+          first: (batch * SYNC_LIST_BATCH) as u64,
+          hashes: hashes.to_vec(),
+        },
+      )
+      .await?;
+    }
+  }
+  send_sync(dc, &SyncMessage::Ready).await?;
+
+  let mut current: Option<SyncWrite> = None;
+  let (mut updated, mut received) = (0usize, 0u64);
+  loop {
+    let data = match events.recv().await {
+      Some(ChannelEvent::Binary(data)) => data,
+      Some(ChannelEvent::Text(text)) => {
+        match serde_json::from_slice::<SyncMessage>(&text)? {
+          SyncMessage::File { path, size, mtime } => {
+            let target = sync_target_path(root, &path)?;
+            if let Some(parent) = target.parent() {
+              tokio::fs::create_dir_all(parent).await?;
+            }
+            // Patched in place: blocks the sender skips are already correct on disk.
+            let file = tokio::fs::OpenOptions::new()
+              .write(true)
+              .create(true)
+              .truncate(false)
+              .open(&target)
+              .await
+              .with_context(|| format!("open {}", target.display()))?;
+            current = Some(SyncWrite { file, path, size, mtime, block: None });
+          }
+          SyncMessage::Block { index, sha256 } => {
+            let write = current.as_mut().ok_or_else(|| anyhow!("Block sent outside a file"))?;
+            finish_sync_block(write)?;
+            let range = sync_block_range(index, write.size)
+              .ok_or_else(|| anyhow!("Block {index} is past the end of {}", write.path))?;
+            write.file.seek(std::io::SeekFrom::Start(range.start)).await?;
+            write.block = Some(SyncBlock {
+              index,
+              sha256,
+              hasher: Sha256::new(),
+              remaining: range.end - range.start,
+            });
+          }
+          SyncMessage::FileEnd => {
+            let write = current.take().ok_or_else(|| anyhow!("File end sent outside a file"))?;
+            log_line("[sync] updated", &finish_sync_file(write).await?);
+            updated += 1;
+          }
+          SyncMessage::Done => break,
+          other => return Err(anyhow!("Unexpected {other:?} during the transfer")),
+        }
+        continue;
+      }
+      Some(ChannelEvent::Open) => continue,
+      Some(ChannelEvent::Closed) | None => return Err(anyhow!("Channel closed before the sync finished")),
+    };
+    let write = current.as_mut().ok_or_else(|| anyhow!("Data sent outside a file"))?;
+    let plain = match crypto {
+      Some(crypto) => decrypt_frame(crypto, &data).context("Decrypt failed (wrong key?)")?,
+      None => data.to_vec(),
+    };
+    let block = write.block.as_mut().ok_or_else(|| anyhow!("Data sent outside a block"))?;
+    if plain.len() as u64 > block.remaining {
+      return Err(anyhow!("Block {} of {} is longer than the file's size allows", block.index, write.path));
+    }
+    block.remaining -= plain.len() as u64;
+    block.hasher.update(&plain);
+    write.file.write_all(&plain).await?;
+    received += plain.len() as u64;
+  }
+
+  send_sync(dc, &SyncMessage::Complete).await?;
+  wait_for_drain(dc).await;
+  log_line(
+    "[sync] done",
+    &format!("{updated} updated, {} unchanged, {received} bytes received", local.len().saturating_sub(updated)),
+  );
+  Ok(())
+}
+
+/// Cuts a patched file to the sender's size, dropping blocks past its end, and stamps the
+/// sender's mtime. Returns the file's relative path.
+async fn finish_sync_file(mut write: SyncWrite) -> Result<String> {
+  finish_sync_block(&mut write)?;
+  // Every block up to the sender's size is either on disk already or was just sent, so this
+  // only ever shortens the file; a sender that skipped blocks must not grow it with zeros.
+  let len = write.file.metadata().await?.len();
+  if len < write.size {
+    return Err(anyhow!("{} ended after {len} of its {} bytes", write.path, write.size));
+  }
+  write.file.set_len(write.size).await?;
+  write.file.flush().await?;
+  // The mtime is set last, so an interrupted update fails the quick check next time.
+  let file = write.file.into_std().await;
+  file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(write.mtime))?;
+  Ok(write.path)
+}
+
+fn finish_sync_block(write: &mut SyncWrite) -> Result<()> {
+  if let Some(block) = write.block.take() {
+    if block.remaining > 0 {
+      return Err(anyhow!("Block {} of {} ended {} bytes short", block.index, write.path, block.remaining));
+    }
+    if hex_digest(block.hasher) != block.sha256 {
+      return Err(CliError::Checksum(format!("sha256 mismatch in block {} of {}", block.index, write.path)).into());
+    }
+  }
+  Ok(())
+}
+
+/// The byte range block `index` covers in a file of `size` bytes, or `None` when the block
+/// starts at or past the end.
+fn sync_block_range(index: u64, size: u64) -> Option<std::ops::Range<u64>> {
+  let start = index.checked_mul(SYNC_BLOCK_SIZE).filter(|&start| start < size)?;
+  Some(start..size.min(start + SYNC_BLOCK_SIZE))
+}
+
+async fn send_sync(dc: &RTCDataChannel, msg: &SyncMessage) -> Result<()> {
+  dc.send_text(serde_json::to_string(msg)?).await?;
+  Ok(())
+}
+
+async fn next_sync_message(events: &mut mpsc::Receiver<ChannelEvent>) -> Result<SyncMessage> {
+  loop {
+    match events.recv().await {
+      Some(ChannelEvent::Text(text)) => return Ok(serde_json::from_slice(&text)?),
+      Some(ChannelEvent::Open) => continue,
+      Some(ChannelEvent::Binary(_)) => return Err(anyhow!("Unexpected data before the transfer")),
+      Some(ChannelEvent::Closed) | None => return Err(anyhow!("Channel closed before the sync finished")),
+    }
+  }
+}
+
+/// The quick check: a file whose size and mtime both match is assumed unchanged, as rsync
+/// does by default. Everything else, including files the receiver lacks, gets sent.
+fn changed_sync_entries<'a>(
+  local: &'a [(SyncEntry, PathBuf)],
+  remote: &HashMap<String, SyncEntry>,
+) -> Vec<&'a SyncEntry> {
+  local
+    .iter()
+    .map(|(entry, _)| entry)
+    .filter(|entry| {
+      remote
+        .get(&entry.path)
+        .is_none_or(|theirs| theirs.size != entry.size || theirs.mtime != entry.mtime)
+    })
+    .collect()
+}
+
+/// Changed files the receiver already has content for, the only ones worth hashing there.
+fn sync_hashes_wanted(changed: &[&SyncEntry], remote: &HashMap<String, SyncEntry>) -> Vec<String> {
+  changed
+    .iter()
+    .filter(|entry| remote.get(&entry.path).is_some_and(|theirs| theirs.size > 0))
+    .map(|entry| entry.path.clone())
+    .collect()
+}
+
+/// Indexes of our blocks that the receiver lacks or holds different content for. Its blocks
+/// past the end of our file need nothing sent, as it truncates to our size.
+fn differing_blocks(ours: &[String], theirs: &[String]) -> Vec<usize> {
+  ours
+    .iter()
+    .enumerate()
+    .filter(|(index, sha256)| theirs.get(*index) != Some(*sha256))
+    .map(|(index, _)| index)
+    .collect()
+}
+
+fn hash_sync_block(block: &[u8]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(block);
+  hex_digest(hasher)
+}
+
+/// The SHA-256 of each `SYNC_BLOCK_SIZE` block of the file at `path`.
+async fn hash_sync_blocks(path: &Path) -> Result<Vec<String>> {
+  let mut file = File::open(path).await.with_context(|| format!("open {}", path.display()))?;
+  let mut block = Vec::with_capacity(SYNC_BLOCK_SIZE as usize);
+  let mut hashes = Vec::new();
+  loop {
+    block.clear();
+    (&mut file).take(SYNC_BLOCK_SIZE).read_to_end(&mut block).await?;
+    if block.is_empty() {
+      return Ok(hashes);
+    }
+    hashes.push(hash_sync_block(&block));
+  }
+}
+
+/// Regular files under `root` with `/`-separated relative paths, sorted. Symlinks are not
+/// followed.
+async fn walk_sync_dir(root: &Path) -> Result<Vec<(SyncEntry, PathBuf)>> {
+  let mut files = Vec::new();
+  let mut dirs = vec![root.to_path_buf()];
+  while let Some(dir) = dirs.pop() {
+    let mut entries = tokio::fs::read_dir(&dir)
+      .await
+      .with_context(|| format!("read {}", dir.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+      let file_type = entry.file_type().await?;
+      let path = entry.path();
+      if file_type.is_dir() {
+        dirs.push(path);
+        continue;
+      }
+      if !file_type.is_file() {
+        continue;
+      }
+      let relative = path
+        .strip_prefix(root)?
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>();
+      let Some(relative) = relative else {
+        log_line("[sync] skipped", &format!("{} (name is not UTF-8)", path.display()));
+        continue;
+      };
+      let metadata = entry.metadata().await?;
+      let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        // Whole seconds: filesystems and platforms keep different sub-second precision, and a
+        // copy that lost it would otherwise count as changed on every run.
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+      let entry = SyncEntry {
+        path: relative.join("/"),
+        size: metadata.len(),
+        mtime,
+      };
+      files.push((entry, path));
+    }
+  }
+  files.sort_by(|a, b| a.0.path.cmp(&b.0.path));
+  Ok(files)
+}
+
+/// Resolves a path from the sender under `root`, refusing anything that could escape it.
+fn sync_target_path(root: &Path, relative: &str) -> Result<PathBuf> {
+  let unsafe_path = || anyhow!("Refusing unsafe path '{relative}' from the sender");
+  let real_root = root.canonicalize().with_context(|| format!("resolve {}", root.display()))?;
+  let mut path = root.to_path_buf();
+  let mut exists = true;
+  for part in relative.split('/') {
+    let mut components = Path::new(part).components();
+    match (components.next(), components.next()) {
+      (Some(Component::Normal(_)), None) => path.push(part),
+      _ => return Err(unsafe_path()),
+    }
+    // A symlink already in DST must not lead the write outside it, even a dangling one.
+    exists = exists && path.symlink_metadata().is_ok();
+    if exists && !path.canonicalize().is_ok_and(|real| real.starts_with(&real_root)) {
+      return Err(unsafe_path());
+    }
+  }
+  Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::tests::TempDir;
+  use std::env;
+
+  #[test]
+  fn sync_target_path_stays_inside_the_root() {
+    let dir = TempDir::new();
+    let root = &dir.0;
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    assert_eq!(sync_target_path(root, "sub/new/file.txt").unwrap(), root.join("sub/new/file.txt"));
+    for unsafe_path in ["../escape", "sub/../../escape", "/etc/passwd", "sub//file", "./file", ""] {
+      assert!(sync_target_path(root, unsafe_path).is_err(), "{unsafe_path}");
+    }
+    #[cfg(unix)]
+    {
+      std::os::unix::fs::symlink(env::temp_dir(), root.join("link")).unwrap();
+      std::os::unix::fs::symlink(root.join("sub"), root.join("inside")).unwrap();
+      assert!(sync_target_path(root, "link/file.txt").is_err());
+      assert!(sync_target_path(root, "inside/file.txt").is_ok());
+    }
+  }
+
+  fn sync_entry(path: &str, size: u64, mtime: u64) -> SyncEntry {
+    SyncEntry {
+      path: path.to_string(),
+      size,
+      mtime,
+    }
+  }
+
+  #[test]
+  fn sync_picks_changed_files_and_which_to_hash() {
+    let local: Vec<(SyncEntry, PathBuf)> = [
+      sync_entry("same.txt", 10, 1),
+      sync_entry("touched.txt", 10, 2),
+      sync_entry("grown.txt", 20, 1),
+      sync_entry("was-empty.txt", 5, 1),
+      sync_entry("new.txt", 5, 1),
+    ]
+    .into_iter()
+    .map(|entry| (entry, PathBuf::new()))
+    .collect();
+    let remote: HashMap<String, SyncEntry> = [
+      sync_entry("same.txt", 10, 1),
+      sync_entry("touched.txt", 10, 1),
+      sync_entry("grown.txt", 10, 1),
+      sync_entry("was-empty.txt", 0, 1),
+      sync_entry("only-theirs.txt", 5, 1),
+    ]
+    .into_iter()
+    .map(|entry| (entry.path.clone(), entry))
+    .collect();
+    let changed = changed_sync_entries(&local, &remote);
+    let paths: Vec<&str> = changed.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, ["touched.txt", "grown.txt", "was-empty.txt", "new.txt"]);
+    assert_eq!(sync_hashes_wanted(&changed, &remote), ["touched.txt", "grown.txt"]);
+  }
+
+  #[test]
+  fn sync_sends_only_blocks_that_differ() {
+    let hashes = |blocks: &[&str]| blocks.iter().map(|block| hash_sync_block(block.as_bytes())).collect::<Vec<_>>();
+    let ours = hashes(&["a", "b", "c"]);
+    assert_eq!(differing_blocks(&ours, &ours), Vec::<usize>::new());
+    assert_eq!(differing_blocks(&ours, &hashes(&["a", "x", "c"])), [1]);
+    assert_eq!(differing_blocks(&ours, &hashes(&["x", "b", "y"])), [0, 2]);
+    // Theirs is shorter: the missing tail is sent.
+    assert_eq!(differing_blocks(&ours, &hashes(&["a"])), [1, 2]);
+    // Theirs is longer: nothing is sent and truncation drops the rest.
+    assert_eq!(differing_blocks(&ours, &hashes(&["a", "b", "c", "d"])), Vec::<usize>::new());
+    // A new file is sent whole.
+    assert_eq!(differing_blocks(&ours, &[]), [0, 1, 2]);
+  }
+
+  #[tokio::test]
+  async fn sync_hashes_blocks_and_truncates_patched_files() {
+    let dir = TempDir::new();
+    let path = dir.0.join("file.bin");
+    let mut content = vec![1u8; SYNC_BLOCK_SIZE as usize];
+    content.extend_from_slice(b"tail");
+    std::fs::write(&path, &content).unwrap();
+    let blocks = hash_sync_blocks(&path).await.unwrap();
+    assert_eq!(blocks, [hash_sync_block(&content[..SYNC_BLOCK_SIZE as usize]), hash_sync_block(b"tail")]);
+
+    let file = tokio::fs::OpenOptions::new().write(true).open(&path).await.unwrap();
+    let write = SyncWrite {
+      file,
+      path: "file.bin".to_string(),
+      size: 3,
+      mtime: 1000,
+      block: None,
+    };
+    assert_eq!(finish_sync_file(write).await.unwrap(), "file.bin");
+    let metadata = std::fs::metadata(&path).unwrap();
+    assert_eq!(metadata.len(), 3);
+    assert_eq!(metadata.modified().unwrap(), SystemTime::UNIX_EPOCH + Duration::from_secs(1000));
+  }
+
+  #[test]
+  fn sync_blocks_stay_inside_the_announced_size() {
+    let size = 2 * SYNC_BLOCK_SIZE + 5;
+    assert_eq!(sync_block_range(0, size), Some(0..SYNC_BLOCK_SIZE));
+    assert_eq!(sync_block_range(2, size), Some(2 * SYNC_BLOCK_SIZE..size));
+    assert_eq!(sync_block_range(3, size), None);
+    assert_eq!(sync_block_range(0, 0), None);
+    assert_eq!(sync_block_range(u64::MAX, u64::MAX), None);
+  }
+}
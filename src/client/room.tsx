@@ -16,6 +16,7 @@ type RoomMessage =
   | { type: "wait"; position?: number }
   | { type: "start"; peerId?: string }
   | { type: "peer-left"; peerId: string }
+  | { type: "refused"; reason?: string }
   | { type: "offer"; from: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "answer"; from: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "candidate"; from: string; sid: number; candidate: RTCIceCandidateInit };
@@ -564,6 +565,13 @@ function RoomApp({ roomId, maxConcurrent }: RoomAppProps) {
           return;
         }
 
+        if (msg.type === "refused") {
+          if (roleRef.current === "answerer") {
+            setStatus(t.status.refused);
+          }
+          return;
+        }
+
         if (msg.type === "peer-left") {
           if (roleRef.current === "offerer") {
             closeOffererPeer(msg.peerId);
@@ -10,6 +10,7 @@ type ServerToClient =
   | { type: "wait"; position?: number }
   | { type: "start"; peerId?: string }
   | { type: "peer-left"; peerId: string }
+  | { type: "refused"; reason?: string }
   | { type: "offer"; from: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "answer"; from: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "candidate"; from: string; sid: number; candidate: RTCIceCandidateInit };
@@ -18,7 +19,8 @@ type ClientToServer =
   | { type: "offer"; to: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "answer"; to: string; sid: number; sdp: RTCSessionDescriptionInit }
   | { type: "candidate"; to: string; sid: number; candidate: RTCIceCandidateInit }
-  | { type: "transfer-done"; peerId: string };
+  | { type: "transfer-done"; peerId: string }
+  | { type: "refuse"; peerId: string; reason?: string };
 
 type SocketAttachment = {
   cid: string;
@@ -106,7 +108,7 @@ export class Room extends DurableObjectBase {
     log("[room] new connection, cid:", clientId, "current sockets:", this.ctx.getWebSockets().length);
     this.closeDuplicateClient(clientId);
     log("[room] after closeDuplicate, sockets:", this.ctx.getWebSockets().length);
-    const role = this.pickRole(clientId);
+    const role = this.pickRole(clientId, url.searchParams.get("role"));
     log("[room] assigned role:", role, "to cid:", clientId);
 
     const pair = new WebSocketPair();
@@ -155,6 +157,17 @@ export class Room extends DurableObjectBase {
       return;
     }
 
+    if (msg.type === "refuse") {
+      if (attachment.role !== "offerer") return;
+      const peerSocket = this.socketByCid(msg.peerId);
+      if (peerSocket) {
+        this.sendJson(peerSocket, { type: "refused", reason: msg.reason });
+        this.setAnswererState(peerSocket, "done");
+      }
+      this.fillSlots();
+      return;
+    }
+
     if (msg.type === "offer" || msg.type === "answer" || msg.type === "candidate") {
       if (msg.type === "offer") {
         if (attachment.role !== "offerer") return;
@@ -247,11 +260,13 @@ export class Room extends DurableObjectBase {
     for (const socket of sockets) this.sendText(socket, payload);
   }
 
-  private pickRole(clientId: string) {
+  private pickRole(clientId: string, requested: string | null) {
     const creatorCid = this.config?.creatorCid;
     if (creatorCid) {
       return creatorCid === clientId ? "offerer" : "answerer";
     }
+    // Long-lived receivers (CLI inbox) ask to stay answerers even when they reach an empty room first.
+    if (requested === "answerer") return "answerer";
     const offerer = this.getOffererSocket();
     if (!offerer) return "offerer";
     return "answerer";